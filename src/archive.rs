@@ -0,0 +1,198 @@
+//! Tiered storage for finished segments.
+//!
+//! When a topic is configured with an [`ArchiveBackend`] (see
+//! [`TopicBuilder::with_archive`]), finished segments are uploaded along with
+//! their `.idx` after each roll. With
+//! [`TopicBuilder::with_local_retention`], archived segments beyond the
+//! retention are then removed from the topic directory. Readers that go past
+//! the oldest local segment download archived ones back transparently.
+//!
+//! Each topic should have its own backend, e.g. [`LocalArchive`] with a
+//! directory per topic.
+//!
+//! [`TopicBuilder::with_archive`]: crate::TopicBuilder::with_archive
+//! [`TopicBuilder::with_local_retention`]: crate::TopicBuilder::with_local_retention
+
+use std::{
+    ffi::OsStr,
+    fmt::Debug,
+    fs,
+    hash::{Hash, Hasher},
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::Result;
+
+/// Kind of files a segment consists of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SegmentFile {
+    /// The `.limlog` file
+    Log,
+    /// The `.idx` file
+    Index,
+}
+
+impl SegmentFile {
+    /// Extension of the file.
+    pub const fn extension(self) -> &'static str {
+        match self {
+            Self::Log => "limlog",
+            Self::Index => "idx",
+        }
+    }
+}
+
+/// Storage that finished segments are archived to.
+///
+/// Methods may block on IO. Readers and the background task call them on
+/// blocking threads, while [`Topic::reader_from`], [`Topic::range`] and
+/// [`Topic::get`] call them on the caller's thread.
+///
+/// [`Topic::reader_from`]: crate::Topic::reader_from
+/// [`Topic::range`]: crate::Topic::range
+/// [`Topic::get`]: crate::Topic::get
+pub trait ArchiveBackend: Debug + Send + Sync + 'static {
+    /// Store `file` of segment `segment` with content read from `data`.
+    /// Uploading the same file again replaces it.
+    ///
+    /// For each segment, the [`SegmentFile::Index`] is uploaded before the
+    /// [`SegmentFile::Log`], so a segment can be considered archived once its
+    /// log is stored.
+    fn upload(&self, segment: &str, file: SegmentFile, data: &mut dyn Read) -> Result<()>;
+
+    /// Copy `file` of segment `segment` to `dest`. Returns `false` if it's not
+    /// archived.
+    fn download(&self, segment: &str, file: SegmentFile, dest: &Path) -> Result<bool>;
+
+    /// Returns names of all archived segments, in any order.
+    fn list(&self) -> Result<Vec<String>>;
+
+    /// Remove both files of segment `segment`, if they're archived. Called
+    /// when the segment is truncated, so the log is removed before the index.
+    ///
+    /// The default implementation fails with [`io::ErrorKind::Unsupported`].
+    /// Backends implementing it must also return `true` from
+    /// [`can_remove`](ArchiveBackend::can_remove).
+    fn remove(&self, segment: &str) -> Result<()> {
+        let _ = segment;
+        Err(io::Error::from(io::ErrorKind::Unsupported).into())
+    }
+
+    /// Whether [`remove`](ArchiveBackend::remove) is supported. Truncating a
+    /// topic archived with a backend that returns `false` fails with
+    /// [`io::ErrorKind::Unsupported`] before anything is changed.
+    fn can_remove(&self) -> bool {
+        false
+    }
+}
+
+/// An [`ArchiveBackend`] that stores segments in a local directory.
+#[derive(Debug, Clone)]
+pub struct LocalArchive {
+    dir: PathBuf,
+}
+
+impl LocalArchive {
+    /// Create the backend with `dir`, which will be created if it doesn't
+    /// exist.
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    fn path(&self, segment: &str, file: SegmentFile) -> PathBuf {
+        self.dir.join(segment).with_extension(file.extension())
+    }
+}
+
+impl ArchiveBackend for LocalArchive {
+    fn upload(&self, segment: &str, file: SegmentFile, data: &mut dyn Read) -> Result<()> {
+        let path = self.path(segment, file);
+        copy_atomic(data, &path)
+    }
+
+    fn download(&self, segment: &str, file: SegmentFile, dest: &Path) -> Result<bool> {
+        match fs::File::open(self.path(segment, file)) {
+            Ok(mut src) => copy_atomic(&mut src, dest).map(|_| true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        let mut names = vec![];
+
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(OsStr::to_str) != Some(SegmentFile::Log.extension()) {
+                continue;
+            }
+            if let Some(stem) = path.file_stem().and_then(OsStr::to_str) {
+                names.push(stem.to_owned());
+            }
+        }
+
+        Ok(names)
+    }
+
+    fn remove(&self, segment: &str) -> Result<()> {
+        for file in [SegmentFile::Log, SegmentFile::Index] {
+            match fs::remove_file(self.path(segment, file)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn can_remove(&self) -> bool {
+        true
+    }
+}
+
+/// Write to a temporary file and rename it to `dest`, so `dest` is never
+/// partially written.
+pub(crate) fn copy_atomic(data: &mut dyn Read, dest: &Path) -> Result<()> {
+    let ext = dest.extension().and_then(OsStr::to_str).unwrap_or_default();
+    let tmp = dest.with_extension(format!("{ext}.tmp"));
+    let mut file = fs::File::create(&tmp)?;
+    io::copy(data, &mut file)?;
+    file.sync_all()?;
+    fs::rename(tmp, dest)?;
+    Ok(())
+}
+
+/// Configured [`ArchiveBackend`] of a topic. Compared by identity.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Archive(Option<Arc<dyn ArchiveBackend>>);
+
+impl Archive {
+    pub fn new(backend: impl ArchiveBackend) -> Self {
+        Self(Some(Arc::new(backend)))
+    }
+
+    pub fn backend(&self) -> Option<&dyn ArchiveBackend> {
+        self.0.as_deref()
+    }
+
+    fn addr(&self) -> Option<*const ()> {
+        self.0.as_ref().map(|b| Arc::as_ptr(b).cast::<()>())
+    }
+}
+
+impl PartialEq for Archive {
+    fn eq(&self, other: &Self) -> bool {
+        self.addr() == other.addr()
+    }
+}
+
+impl Eq for Archive {}
+
+impl Hash for Archive {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.addr().hash(state);
+    }
+}
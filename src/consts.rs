@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use smallvec::SmallVec;
 
 pub const INDEX_MAGIC: &[u8; 8] = b"LIM_IDX\0";
@@ -9,8 +11,8 @@ pub const HEADER_SIZE: usize = 16;
 /// `UUID` (16) + `OFFSET` (8)
 pub const INDEX_SIZE: usize = 24;
 
-/// `UUID` (16) + `KEY_LEN` (8) + `VALUE_LEN` (8) + `KEY` (0) + `VALUE` (0)
-pub const MIN_LOG_SIZE: usize = 32;
+/// `UUID` (16) + `BODY_LEN` (8) + `BODY` (0)
+pub const MIN_LOG_SIZE: usize = 24;
 
 /// Default size of the log file, 4GB.
 pub const DEFAULT_LOG_SIZE: u64 = 1 << 32;
@@ -18,7 +20,18 @@ pub const DEFAULT_LOG_SIZE: u64 = 1 << 32;
 /// Default size of the index file, 16MB.
 pub const DEFAULT_INDEX_SIZE: u64 = 1 << 24;
 
+/// Max number of logs written in one batch, see
+/// [`FormatVersion::V2`](crate::formats::FormatVersion::V2).
+pub const MAX_BATCH_SIZE: u64 = 1 << 10;
+
 /// Default size of the channel, 16 items.
 pub const DEFAULT_CHANNEL_SIZE: u32 = 1 << 4;
 
+/// Default interval of read-only topics to check for new logs, 100ms.
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Max time logs are written before they're synced to the disk and the
+/// committed watermark is advanced past them, 10ms.
+pub const SYNC_INTERVAL: Duration = Duration::from_millis(10);
+
 pub type SmallBytes = SmallVec<[u8; 62]>;
@@ -0,0 +1,149 @@
+//! Encryption at rest of log bodies, with feature `encryption`.
+//!
+//! With an [`EncryptionKey`] set by [`TopicBuilder::with_encryption_key`],
+//! bodies are encrypted with XChaCha20-Poly1305 before they are written, and
+//! decrypted transparently when read. The id of the key is recorded in the
+//! header of each segment, so a topic can hold segments encrypted with
+//! different keys as long as all of them are provided. UUIDs and headers of
+//! logs are stored in plaintext, and UUIDs are authenticated along with the
+//! bodies.
+//!
+//! Nonces are derived from the name of the segment and the offset of the
+//! record, which are never reused since segments are not written again once
+//! rolled.
+//!
+//! Keys are rotated with [`Topic::rotate_key`], which rolls the segment before
+//! the next log is written so that the new segment is encrypted with the new
+//! key.
+//!
+//! [`TopicBuilder::with_encryption_key`]: crate::TopicBuilder::with_encryption_key
+//! [`Topic::rotate_key`]: crate::Topic::rotate_key
+
+use std::{collections::BTreeMap, fmt};
+
+#[cfg(feature = "encryption")]
+use chacha20poly1305::{
+    aead::{Aead, Payload},
+    KeyInit, XChaCha20Poly1305, XNonce,
+};
+#[cfg(feature = "encryption")]
+use uuid7::Uuid;
+
+#[cfg(feature = "encryption")]
+use crate::consts::SmallBytes;
+use crate::{formats::Log, ErrorType, Result};
+
+/// A 256-bit key, identified by `id` in headers of segments it encrypts.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct EncryptionKey {
+    id: u8,
+    key: [u8; 32],
+}
+
+impl EncryptionKey {
+    #[cfg(feature = "encryption")]
+    pub const fn new(id: u8, key: [u8; 32]) -> Self {
+        Self { id, key }
+    }
+
+    pub const fn id(&self) -> u8 {
+        self.id
+    }
+
+    #[cfg(feature = "encryption")]
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(&self.key.into())
+    }
+
+    /// Encrypt the body of `log` to be written at `offset` of `segment`.
+    #[cfg(feature = "encryption")]
+    pub(crate) fn encrypt(&self, segment: &str, offset: usize, mut log: Log) -> Result<Log> {
+        let payload = Payload {
+            msg: &log.body,
+            aad: log.uuid.as_bytes(),
+        };
+        log.body = nonce(segment, offset)
+            .and_then(|nonce| self.cipher().encrypt(&nonce, payload).ok())
+            .map(SmallBytes::from_vec)
+            .ok_or_else(|| {
+                ErrorType::Encode(format!("Failed to encrypt log {}", log.uuid).into())
+            })?;
+
+        Ok(log)
+    }
+
+    /// Decrypt the body of `log` read at `offset` of `segment`.
+    #[cfg(feature = "encryption")]
+    pub(crate) fn decrypt(&self, segment: &str, offset: usize, mut log: Log) -> Result<Log> {
+        let payload = Payload {
+            msg: &log.body,
+            aad: log.uuid.as_bytes(),
+        };
+        log.body = nonce(segment, offset)
+            .and_then(|nonce| self.cipher().decrypt(&nonce, payload).ok())
+            .map(SmallBytes::from_vec)
+            .ok_or(ErrorType::Decrypt(log.uuid))?;
+
+        Ok(log)
+    }
+
+    // Keys can't be created without the feature, so these are never called
+    #[cfg(not(feature = "encryption"))]
+    #[allow(clippy::missing_const_for_fn, clippy::needless_pass_by_value)]
+    pub(crate) fn encrypt(&self, _segment: &str, _offset: usize, _log: Log) -> Result<Log> {
+        Err(ErrorType::MissingKey(self.id))
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    #[allow(clippy::missing_const_for_fn, clippy::needless_pass_by_value)]
+    pub(crate) fn decrypt(&self, _segment: &str, _offset: usize, _log: Log) -> Result<Log> {
+        Err(ErrorType::MissingKey(self.id))
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptionKey")
+            .field("id", &self.id)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Keys of a topic. The active one encrypts new segments, and all of them can
+/// decrypt.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub(crate) struct Keyring {
+    keys: BTreeMap<u8, EncryptionKey>,
+    active: Option<u8>,
+}
+
+impl Keyring {
+    /// Add `key` and make it the active one, replacing the key with the same
+    /// id if any.
+    #[cfg(feature = "encryption")]
+    pub fn insert(&mut self, key: EncryptionKey) {
+        self.active = Some(key.id);
+        self.keys.insert(key.id, key);
+    }
+
+    pub fn active(&self) -> Option<&EncryptionKey> {
+        self.active.and_then(|id| self.keys.get(&id))
+    }
+
+    pub fn get(&self, id: u8) -> Result<&EncryptionKey> {
+        self.keys.get(&id).ok_or(ErrorType::MissingKey(id))
+    }
+}
+
+#[cfg(feature = "encryption")]
+/// Nonce of the record at `offset` of `segment`. Returns `None` if the name of
+/// the segment is not a UUID.
+fn nonce(segment: &str, offset: usize) -> Option<XNonce> {
+    let segment = segment.parse::<Uuid>().ok()?;
+
+    let mut nonce = XNonce::default();
+    nonce[..16].copy_from_slice(segment.as_bytes());
+    nonce[16..].copy_from_slice(&(offset as u64).to_le_bytes());
+
+    Some(nonce)
+}
@@ -1,4 +1,7 @@
+use std::{path::PathBuf, sync::Arc};
+
 use thiserror::Error;
+use uuid7::Uuid;
 
 /// The Limlog error type.
 #[derive(Debug, Error)]
@@ -18,8 +21,52 @@ pub enum ErrorType {
     #[error("Bincode error: {0}")]
     Bincode(#[from] bincode::Error),
 
+    #[error("Encode error: {0}")]
+    Encode(Box<dyn std::error::Error + Send + Sync>),
+
+    #[error("Decode error: {0}")]
+    Decode(Box<dyn std::error::Error + Send + Sync>),
+
+    #[error("No encryption key with id {0}")]
+    MissingKey(u8),
+
+    #[error("Failed to decrypt log {0}, it's corrupted or the key is wrong")]
+    Decrypt(Uuid),
+
+    #[error("UUID {uuid} is not after the last written one {last}")]
+    OutOfOrder { uuid: Uuid, last: Uuid },
+
+    #[error("Log has headers, but record headers are not enabled for the topic")]
+    HeadersDisabled,
+
+    #[error("Logs at the read position were truncated")]
+    Truncated,
+
+    #[error("Topic is opened read-only")]
+    ReadOnly,
+
+    #[error("Topic directory {} is locked by another writer", .0.display())]
+    Locked(PathBuf),
+
+    #[error("Topic setting {field} can't be changed from {stored} to {configured}")]
+    ConfigMismatch {
+        field: &'static str,
+        stored: u64,
+        configured: u64,
+    },
+
     #[error("Shutdown signal issued")]
     Shutdown,
+
+    #[error("Background task failed: {0}")]
+    Background(Arc<ErrorType>),
+
+    #[error("Protocol error: {0}")]
+    Protocol(String),
+
+    #[cfg(feature = "server")]
+    #[error("Server error: {0}")]
+    Server(String),
 }
 
 /// A specialized [`Result`] type for Limlog.
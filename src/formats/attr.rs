@@ -0,0 +1,92 @@
+/// Attributes in the [`Header`](super::Header) of `.limlog` and `.idx` files.
+///
+/// | Field                 | Size    |
+/// | --------------------- | ------- |
+/// | committed (u48 LE)    | 6 bytes |
+/// | flags                 | 1 byte  |
+/// | key id                | 1 byte  |
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Attributes {
+    /// Length of committed data after the header, only meaningful with
+    /// [`Attributes::WATERMARK`].
+    pub committed: u64,
+    pub flags: u8,
+    /// Id of the key records are encrypted with, only meaningful with
+    /// [`Attributes::ENCRYPTED`].
+    pub key_id: u8,
+}
+
+impl Attributes {
+    /// `committed` is maintained while writing. Files written before it was
+    /// introduced don't have this flag, and their length is the committed
+    /// length.
+    pub const WATERMARK: u8 = 1 << 0;
+    /// The file was closed properly, i.e. truncated to the committed length.
+    pub const CLEAN: u8 = 1 << 1;
+    /// Only in `.idx`, not every log is indexed.
+    pub const SPARSE: u8 = 1 << 2;
+    /// Only in `.limlog`, records carry headers after the body. See
+    /// [`RecordFormat`](super::RecordFormat).
+    pub const HEADERS: u8 = 1 << 3;
+    /// Only in `.limlog`, bodies of records are encrypted with the key of
+    /// `key_id`.
+    pub const ENCRYPTED: u8 = 1 << 4;
+    /// Only in `.limlog`, bodies of records are compressed with
+    /// [`Compression::Snappy`](crate::Compression::Snappy).
+    pub const SNAPPY: u8 = 1 << 5;
+    /// Only in `.limlog`, records are grouped in batches, i.e.
+    /// [`FormatVersion::V2`](super::FormatVersion::V2).
+    pub const BATCHED: u8 = 1 << 6;
+
+    /// Max value of `committed`
+    pub const MAX_COMMITTED: u64 = (1 << 48) - 1;
+
+    pub const fn has(&self, flag: u8) -> bool {
+        self.flags & flag == flag
+    }
+
+    /// Returns the committed length if it's maintained.
+    pub const fn watermark(&self) -> Option<u64> {
+        if self.has(Self::WATERMARK) {
+            Some(self.committed)
+        } else {
+            None
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; 8] {
+        debug_assert!(self.committed <= Self::MAX_COMMITTED);
+
+        let mut bytes = self.committed.to_le_bytes();
+        bytes[6] = self.flags;
+        bytes[7] = self.key_id;
+        bytes
+    }
+
+    pub const fn from_bytes(mut bytes: [u8; 8]) -> Self {
+        let (flags, key_id) = (bytes[6], bytes[7]);
+        bytes[6] = 0;
+        bytes[7] = 0;
+
+        Self {
+            committed: u64::from_le_bytes(bytes),
+            flags,
+            key_id,
+        }
+    }
+}
+
+#[test]
+fn test_attributes() {
+    let attr = Attributes {
+        committed: 0x1234_5678_9abc,
+        flags: Attributes::WATERMARK | Attributes::CLEAN,
+        key_id: 7,
+    };
+    let bytes = attr.to_bytes();
+
+    assert_eq!(bytes, [0xbc, 0x9a, 0x78, 0x56, 0x34, 0x12, 0b11, 7]);
+    assert_eq!(Attributes::from_bytes(bytes), attr);
+    assert_eq!(attr.watermark(), Some(0x1234_5678_9abc));
+    assert_eq!(Attributes::default().watermark(), None);
+}
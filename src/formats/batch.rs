@@ -0,0 +1,328 @@
+use uuid7::Uuid;
+
+use super::{FormatVersion, Headers, Log, RecordFormat};
+use crate::consts::SmallBytes;
+
+/// `LEN` (4) + `COUNT` (4) + `CRC` (4) + `BASE_UUID` (16)
+pub const BATCH_HEADER_SIZE: usize = 28;
+
+/// Records decoded at once, each with its offset from the start of the
+/// decoded bytes. A single record in [`FormatVersion::V1`], or a whole batch
+/// in [`FormatVersion::V2`].
+pub type Records = Vec<(u64, Log)>;
+
+impl RecordFormat {
+    /// Length of `log` encoded in a batch after a record with UUID `prev`, or
+    /// as the first record of a batch with base UUID `prev`. The body must be
+    /// compressed already, and the authentication tag is included if it's to
+    /// be encrypted. Only meaningful in [`FormatVersion::V2`].
+    pub fn record_len(self, prev: Uuid, log: &Log) -> usize {
+        let body = log.body.len() + self.tag_size();
+        let headers = if self.headers {
+            varint_len(log.headers.len() as _)
+                + log
+                    .headers
+                    .iter()
+                    .map(|(k, v)| {
+                        varint_len(k.len() as _) + k.len() + varint_len(v.len() as _) + v.len()
+                    })
+                    .sum::<usize>()
+        } else {
+            0
+        };
+
+        varint_len(delta(prev, log.uuid)) + varint_len(body as _) + body + headers
+    }
+
+    /// Encode `logs` as a batch into `buf`, which must be at least
+    /// [`BATCH_HEADER_SIZE`] plus [`record_len`](RecordFormat::record_len) of
+    /// each log long. Same as [`encode_into`](RecordFormat::encode_into),
+    /// bodies are written as is. Returns the length of the batch.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `logs` is empty or `buf` is too short.
+    pub fn encode_batch_into(self, buf: &mut [u8], logs: &[Log]) -> usize {
+        let base = logs[0].uuid;
+
+        let mut w = Writer {
+            buf,
+            pos: BATCH_HEADER_SIZE,
+        };
+        let mut prev = base;
+        for log in logs {
+            w.varint(delta(prev, log.uuid));
+            w.varint(log.body.len() as _);
+            w.put(&log.body);
+            if self.headers {
+                w.varint(log.headers.len() as _);
+                for (k, v) in &log.headers {
+                    w.varint(k.len() as _);
+                    w.put(k);
+                    w.varint(v.len() as _);
+                    w.put(v);
+                }
+            }
+            prev = log.uuid;
+        }
+
+        let len = w.pos;
+        let buf = w.buf;
+        buf[12..28].copy_from_slice(base.as_bytes());
+        seal(&mut buf[..len], logs.len());
+
+        len
+    }
+
+    /// Cut the batch at the start of `data` to its first `count` records, by
+    /// rewriting its header in place. Records are not moved, so the batch
+    /// just ends earlier. Returns the new length of the batch, or `None` if
+    /// `data` doesn't hold `count` records.
+    ///
+    /// Only the base UUID and the records are read, so a batch that is cut
+    /// already, or whose header is torn by an interrupted cut, is cut the same.
+    pub fn cut_batch(self, data: &mut [u8], count: usize) -> Option<usize> {
+        let mut r = Reader {
+            data,
+            pos: BATCH_HEADER_SIZE,
+        };
+        for _ in 0..count {
+            r.varint()?;
+            r.bytes()?;
+            if self.headers {
+                for _ in 0..r.varint()? {
+                    r.bytes()?;
+                    r.bytes()?;
+                }
+            }
+        }
+
+        let len = r.pos;
+        seal(&mut data[..len], count);
+
+        Some(len)
+    }
+
+    /// Decode the records at the start of `data`, i.e. a single record in
+    /// [`FormatVersion::V1`] or a batch in [`FormatVersion::V2`]. Returns
+    /// `None` if they're not fully written yet, and the length of the records
+    /// along with them otherwise.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn decode_batch(self, data: &[u8]) -> bincode::Result<Option<(Records, u64)>> {
+        if self.version == FormatVersion::V1 {
+            return self
+                .decode(data)
+                .map(|res| res.map(|(log, len)| (vec![(0, log)], len)));
+        }
+
+        if data.len() < BATCH_HEADER_SIZE {
+            return Ok(None);
+        }
+
+        let u32_at = |at: usize| u32::from_le_bytes(data[at..at + 4].try_into().unwrap());
+        let len = BATCH_HEADER_SIZE + u32_at(0) as usize;
+        let count = u32_at(4);
+        if data.len() < len {
+            return Ok(None);
+        }
+        if count == 0 || checksum(&data[..len]) != u32_at(8) {
+            return Err(corrupted());
+        }
+
+        let mut r = Reader {
+            data: &data[..len],
+            pos: BATCH_HEADER_SIZE,
+        };
+        let mut prev = Uuid::from(<[u8; 16]>::try_from(&data[12..28]).unwrap());
+        let mut records = Vec::with_capacity(count as _);
+        for _ in 0..count {
+            let offset = r.pos as u64;
+            let uuid = undelta(prev, r.varint().ok_or_else(corrupted)?);
+            let body = r.bytes().ok_or_else(corrupted)?;
+            let headers = if self.headers {
+                (0..r.varint().ok_or_else(corrupted)?)
+                    .map(|_| r.bytes().zip(r.bytes()))
+                    .collect::<Option<Headers>>()
+                    .ok_or_else(corrupted)?
+            } else {
+                Headers::new()
+            };
+
+            records.push((
+                offset,
+                Log {
+                    uuid,
+                    body,
+                    headers,
+                },
+            ));
+            prev = uuid;
+        }
+
+        if r.pos != len {
+            return Err(corrupted());
+        }
+
+        Ok(Some((records, len as _)))
+    }
+}
+
+/// Write the length, count and CRC of `batch` to its header. The base UUID
+/// and records must be written already.
+fn seal(batch: &mut [u8], count: usize) {
+    let len = batch.len() - BATCH_HEADER_SIZE;
+    batch[0..4].copy_from_slice(&(len as u32).to_le_bytes());
+    batch[4..8].copy_from_slice(&(count as u32).to_le_bytes());
+    let crc = checksum(batch);
+    batch[8..12].copy_from_slice(&crc.to_le_bytes());
+}
+
+/// CRC of a batch, covering everything but the CRC itself
+fn checksum(batch: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&batch[..8]);
+    hasher.update(&batch[12..]);
+    hasher.finalize()
+}
+
+fn corrupted() -> bincode::Error {
+    bincode::ErrorKind::Custom("Corrupted batch".to_owned()).into()
+}
+
+/// Zigzag encoded difference from `prev` to `uuid`, as integers
+#[allow(clippy::cast_possible_wrap, clippy::cast_sign_loss)]
+const fn delta(prev: Uuid, uuid: Uuid) -> u128 {
+    let d = u128::from_be_bytes(*uuid.as_bytes()).wrapping_sub(u128::from_be_bytes(*prev.as_bytes()))
+        as i128;
+    ((d << 1) ^ (d >> 127)) as u128
+}
+
+#[allow(clippy::cast_possible_wrap, clippy::cast_sign_loss)]
+fn undelta(prev: Uuid, delta: u128) -> Uuid {
+    let d = ((delta >> 1) as i128) ^ -((delta & 1) as i128);
+    u128::from_be_bytes(*prev.as_bytes())
+        .wrapping_add(d as u128)
+        .to_be_bytes()
+        .into()
+}
+
+const fn varint_len(mut n: u128) -> usize {
+    let mut len = 1;
+    while n >= 0x80 {
+        n >>= 7;
+        len += 1;
+    }
+    len
+}
+
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl Writer<'_> {
+    fn put(&mut self, bytes: &[u8]) {
+        self.buf[self.pos..self.pos + bytes.len()].copy_from_slice(bytes);
+        self.pos += bytes.len();
+    }
+
+    /// LEB128
+    fn varint(&mut self, mut n: u128) {
+        while n >= 0x80 {
+            self.buf[self.pos] = n as u8 | 0x80;
+            self.pos += 1;
+            n >>= 7;
+        }
+        self.buf[self.pos] = n as u8;
+        self.pos += 1;
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn varint(&mut self) -> Option<u128> {
+        let mut n = 0u128;
+        for shift in (0..128).step_by(7) {
+            let byte = *self.data.get(self.pos)?;
+            self.pos += 1;
+            n |= u128::from(byte & 0x7f) << shift;
+            if byte < 0x80 {
+                return Some(n);
+            }
+        }
+        None
+    }
+
+    /// Bytes prefixed with their length
+    fn bytes(&mut self) -> Option<SmallBytes> {
+        let len = usize::try_from(self.varint()?).ok()?;
+        let bytes = self.data.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(SmallBytes::from_slice(bytes))
+    }
+}
+
+#[test]
+fn test_batch() {
+    let logs = [
+        Log::new(b"hello".as_slice()),
+        Log::new(b"world".as_slice()).with_header(b"k".as_slice(), b"v".as_slice()),
+        Log {
+            uuid: Uuid::NIL,
+            ..Log::default()
+        },
+    ];
+
+    for headers in [false, true] {
+        let format = RecordFormat {
+            version: FormatVersion::V2,
+            headers,
+            ..Default::default()
+        };
+
+        let mut len = BATCH_HEADER_SIZE;
+        let mut prev = logs[0].uuid;
+        for log in &logs {
+            len += format.record_len(prev, log);
+            prev = log.uuid;
+        }
+
+        let mut buf = vec![0; len + 4];
+        assert_eq!(format.encode_batch_into(&mut buf, &logs), len);
+        assert_eq!(format.decode_batch(&buf[..len - 1]).unwrap(), None);
+
+        let (records, read) = format.decode_batch(&buf).unwrap().unwrap();
+        assert_eq!(read, len as u64);
+        assert_eq!(records.len(), logs.len());
+        assert_eq!(records[0].0, BATCH_HEADER_SIZE as u64);
+        for ((_, decoded), log) in records.iter().zip(&logs) {
+            assert_eq!(decoded.uuid, log.uuid);
+            assert_eq!(decoded.body, log.body);
+            assert_eq!(decoded.headers.len(), if headers { log.headers.len() } else { 0 });
+        }
+
+        // "hello" takes 7 bytes as the first record, plus the count of headers
+        assert_eq!(
+            format.record_len(logs[0].uuid, &logs[0]),
+            1 + 1 + 5 + usize::from(headers)
+        );
+
+        // Cut to the first two records, twice
+        let cut = records[2].0 as usize;
+        for _ in 0..2 {
+            assert_eq!(format.cut_batch(&mut buf, 2), Some(cut));
+            let (records, read) = format.decode_batch(&buf).unwrap().unwrap();
+            assert_eq!(read, cut as u64);
+            assert_eq!(records.len(), 2);
+            assert_eq!(records[1].1.uuid, logs[1].uuid);
+        }
+        assert_eq!(format.cut_batch(&mut buf[..cut], 3), None);
+
+        buf[cut - 1] ^= 1;
+        assert!(format.decode_batch(&buf).is_err());
+    }
+}
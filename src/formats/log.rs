@@ -1,16 +1,29 @@
+use bincode::Options;
 use serde::{Deserialize, Serialize};
 use uuid7::{uuid7, Uuid};
 
+use super::Attributes;
 use crate::{
+    bincode_option,
     consts::{SmallBytes, HEADER_SIZE, INDEX_MAGIC, INDEX_SIZE, LOG_MAGIC},
+    try_decode,
     util::SubArray,
+    Compression,
 };
 
+/// Key/value pairs of metadata attached to a [`Log`], e.g. content type or
+/// trace id. Keys are not required to be unique.
+pub type Headers = Vec<(SmallBytes, SmallBytes)>;
+
 #[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
 pub struct Log {
     #[serde(with = "uuid_u128_little_endian")]
     pub uuid: Uuid,
     pub body: SmallBytes,
+    /// Only stored in segments written with headers enabled, see
+    /// [`RecordFormat`]. Not part of the serialized `Log`.
+    #[serde(skip)]
+    pub headers: Headers,
 }
 
 impl Log {
@@ -19,9 +32,25 @@ impl Log {
         Self {
             uuid: uuid7(),
             body: body.into(),
+            headers: Headers::new(),
         }
     }
 
+    /// Append a header.
+    #[must_use]
+    pub fn with_header(mut self, key: impl Into<SmallBytes>, value: impl Into<SmallBytes>) -> Self {
+        self.headers.push((key.into(), value.into()));
+        self
+    }
+
+    /// Returns the value of the first header with `key`.
+    pub fn header(&self, key: &[u8]) -> Option<&[u8]> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.as_slice() == key)
+            .map(|(_, v)| v.as_slice())
+    }
+
     /// Specialized short cut of `bincode::Options::serialized_size()`
     #[inline]
     pub fn byte_len(&self) -> usize {
@@ -29,6 +58,168 @@ impl Log {
     }
 }
 
+/// [`Log`] with headers, as stored in segments with [`Attributes::HEADERS`].
+#[derive(Serialize)]
+struct LogWithHeadersRef<'a> {
+    #[serde(with = "uuid_u128_little_endian")]
+    uuid: Uuid,
+    body: &'a SmallBytes,
+    headers: &'a Headers,
+}
+
+#[derive(Deserialize)]
+struct LogWithHeaders {
+    #[serde(with = "uuid_u128_little_endian")]
+    uuid: Uuid,
+    body: SmallBytes,
+    headers: Headers,
+}
+
+/// Version of the layout of records in a `.limlog`, set with
+/// [`TopicBuilder::with_format_version`](crate::TopicBuilder::with_format_version).
+#[derive(Debug, Default, Copy, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum FormatVersion {
+    /// Each record is stored on its own, with a full UUID and a fixed size
+    /// length.
+    #[default]
+    V1,
+    /// Records are grouped in batches with a checksum, and stored with UUIDs
+    /// relative to the previous one and variable size lengths. See
+    /// [`RecordFormat::decode_batch`].
+    V2,
+}
+
+/// Layout of records in a `.limlog`, kept in the attributes of its header so
+/// that segments written with different settings stay readable.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct RecordFormat {
+    pub version: FormatVersion,
+    /// Records carry [`Log::headers`] after the body
+    pub headers: bool,
+    /// Codec bodies are compressed with
+    pub compression: Compression,
+    /// Bodies are encrypted with the key of this id, see
+    /// [`EncryptionKey`](crate::crypto::EncryptionKey)
+    pub key_id: Option<u8>,
+}
+
+impl RecordFormat {
+    /// Length of the authentication tag appended to encrypted bodies
+    pub const TAG_SIZE: usize = 16;
+
+    pub const fn from_attributes(attr: Attributes) -> Self {
+        Self {
+            version: if attr.has(Attributes::BATCHED) {
+                FormatVersion::V2
+            } else {
+                FormatVersion::V1
+            },
+            headers: attr.has(Attributes::HEADERS),
+            compression: if attr.has(Attributes::SNAPPY) {
+                Compression::Snappy
+            } else {
+                Compression::None
+            },
+            key_id: if attr.has(Attributes::ENCRYPTED) {
+                Some(attr.key_id)
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Attributes to record this format with, with nothing committed
+    pub const fn attributes(self) -> Attributes {
+        let mut attr = Attributes {
+            committed: 0,
+            flags: 0,
+            key_id: 0,
+        };
+        if matches!(self.version, FormatVersion::V2) {
+            attr.flags |= Attributes::BATCHED;
+        }
+        if self.headers {
+            attr.flags |= Attributes::HEADERS;
+        }
+        if matches!(self.compression, Compression::Snappy) {
+            attr.flags |= Attributes::SNAPPY;
+        }
+        if let Some(key_id) = self.key_id {
+            attr.flags |= Attributes::ENCRYPTED;
+            attr.key_id = key_id;
+        }
+        attr
+    }
+
+    /// Length of `log` encoded in this format, including the authentication
+    /// tag if the body is to be encrypted. The body must be compressed
+    /// already. Only meaningful in [`FormatVersion::V1`], see
+    /// [`record_len`](RecordFormat::record_len) for batches.
+    #[inline]
+    pub fn byte_len(self, log: &Log) -> usize {
+        let headers = if self.headers {
+            8 + log
+                .headers
+                .iter()
+                .map(|(k, v)| 16 + k.len() + v.len())
+                .sum::<usize>()
+        } else {
+            0
+        };
+        self.tag_size() + log.byte_len() + headers
+    }
+
+    /// Length of the authentication tag appended to bodies in this format
+    pub(super) const fn tag_size(self) -> usize {
+        if self.key_id.is_some() {
+            Self::TAG_SIZE
+        } else {
+            0
+        }
+    }
+
+    /// Encode `log` into `buf` in [`FormatVersion::V1`], which must be at least
+    /// [`byte_len`](RecordFormat::byte_len) long. Headers are dropped if the
+    /// format doesn't carry them. The body is written as is, it must be
+    /// compressed and encrypted beforehand according to the format.
+    pub fn encode_into(self, buf: &mut [u8], log: &Log) -> bincode::Result<()> {
+        if !self.headers {
+            return bincode_option().serialize_into(buf, log);
+        }
+
+        bincode_option().serialize_into(
+            buf,
+            &LogWithHeadersRef {
+                uuid: log.uuid,
+                body: &log.body,
+                headers: &log.headers,
+            },
+        )
+    }
+
+    /// Same as [`try_decode`], with records in this format. Only meaningful in
+    /// [`FormatVersion::V1`], see [`decode_batch`](RecordFormat::decode_batch)
+    /// for all versions.
+    pub fn decode(self, data: &[u8]) -> bincode::Result<Option<(Log, u64)>> {
+        if !self.headers {
+            return try_decode(data);
+        }
+
+        try_decode::<LogWithHeaders>(data).map(|res| {
+            res.map(|(LogWithHeaders { uuid, body, headers }, len)| {
+                (
+                    Log {
+                        uuid,
+                        body,
+                        headers,
+                    },
+                    len,
+                )
+            })
+        })
+    }
+}
+
 #[repr(C, align(8))]
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
 pub struct Header {
@@ -46,6 +237,14 @@ impl Header {
         attributes: [0u8; 8],
     };
 
+    pub const fn attributes(&self) -> Attributes {
+        Attributes::from_bytes(self.attributes)
+    }
+
+    pub fn set_attributes(&mut self, attributes: Attributes) {
+        self.attributes = attributes.to_bytes();
+    }
+
     pub fn as_bytes(&self) -> [u8; HEADER_SIZE] {
         let mut bytes = [0u8; HEADER_SIZE];
         self.write_to(&mut bytes);
@@ -142,15 +341,42 @@ mod uuid_u128_little_endian {
 
 #[test]
 fn test_log_size() {
-    use bincode::Options;
-
     let log = Log::default();
     let len = crate::bincode_option().serialized_size(&log).unwrap();
 
-    assert_eq!(len, log.byte_len() as _);
+    assert_eq!(len, log.byte_len() as u64);
 
     let log = Log::new(vec![1, 1, 4, 5, 1, 4]);
     let len = crate::bincode_option().serialized_size(&log).unwrap();
 
-    assert_eq!(len, log.byte_len() as _);
+    assert_eq!(len, log.byte_len() as u64);
+}
+
+#[test]
+fn test_record_format() {
+    let log = Log::new(vec![1, 1, 4, 5, 1, 4]).with_header(b"content-type".as_slice(), b"text/plain".as_slice());
+
+    for format in [RecordFormat::default(), RecordFormat { headers: true, ..Default::default() }] {
+        let mut buf = vec![0; format.byte_len(&log)];
+        format.encode_into(&mut buf, &log).unwrap();
+
+        let (decoded, len) = format.decode(&buf).unwrap().unwrap();
+        assert_eq!(len, buf.len() as u64);
+        assert_eq!(decoded.body, log.body);
+        assert_eq!(decoded.headers.len(), usize::from(format.headers));
+    }
+
+    // The count of headers, and each with the lengths of the key and value
+    let headers = RecordFormat { headers: true, ..Default::default() };
+    assert_eq!(RecordFormat::default().byte_len(&log), log.byte_len());
+    assert_eq!(headers.byte_len(&log), log.byte_len() + 8 + 16 + 12 + 10);
+
+    assert_eq!(log.header(b"content-type"), Some(b"text/plain".as_slice()));
+    assert_eq!(log.header(b"trace"), None);
+
+    let format = RecordFormat { compression: Compression::Snappy, ..Default::default() };
+    assert_eq!(RecordFormat::from_attributes(format.attributes()), format);
+
+    let format = RecordFormat { version: FormatVersion::V2, key_id: Some(3), ..Default::default() };
+    assert_eq!(RecordFormat::from_attributes(format.attributes()), format);
 }
@@ -1,4 +1,4 @@
-mod_use::mod_use![attr, compaction, log];
+mod_use::mod_use![attr, batch, compaction, log];
 
 /// Several invariants that must be true for the format to work.
 mod format_invariants {
@@ -1,25 +1,43 @@
 #![allow(clippy::inline_always)]
 
 use std::{
-    path::Path,
+    collections::{BTreeMap, BTreeSet, HashSet, VecDeque},
+    ffi::OsStr,
+    fs,
+    io::{ErrorKind, Read},
+    iter,
+    panic::resume_unwind,
+    path::{Path, PathBuf},
+    pin::pin,
     sync::{
-        atomic::{AtomicBool, AtomicUsize, Ordering},
-        Arc,
+        atomic::{fence, AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
     },
 };
 
-use arc_swap::ArcSwap;
-use bincode::Options;
+use arc_swap::{ArcSwap, ArcSwapOption};
 use event_listener::{Event, EventListener};
-use tokio::{select, sync::Notify};
+use kanal::AsyncSender;
+use tap::{Pipe, Tap};
+use tokio::{
+    select,
+    sync::{mpsc, oneshot, Notify},
+    task::{JoinError, JoinHandle},
+    time::{interval, sleep_until, Instant, MissedTickBehavior},
+};
+use tracing::{trace, warn};
+use uuid7::{uuid7, Uuid};
 
 use crate::{
-    consts::{SmallBytes, INDEX_SIZE, MIN_LOG_SIZE},
+    archive::{ArchiveBackend, SegmentFile},
+    consts::{INDEX_SIZE, MAX_BATCH_SIZE, MIN_LOG_SIZE, SYNC_INTERVAL},
+    crypto::{EncryptionKey, Keyring},
     error::Result,
-    formats::{Header, Log, UuidIndex},
-    raw::RawMap,
-    util::{bincode_option, BincodeOptions},
-    ErrorType, TopicBuilder,
+    formats::{Attributes, FormatVersion, Header, Log, RecordFormat, UuidIndex, BATCH_HEADER_SIZE},
+    raw::{MapSlice, RawMap},
+    truncate::{self, Plan, Truncate, Truncation},
+    util::ToTime,
+    DedupWindow, ErrorType, IndexPolicy, OrderingPolicy, TopicBuilder,
 };
 
 #[derive(Debug)]
@@ -29,27 +47,565 @@ pub struct Shared {
     pub stop: Notify,
 
     /// Pointer to the active map. This is rarely changed and is only changed
-    /// when one map is full and a new map is created. Readers should keep a
+    /// when one map is rolled and a new map is created. Readers should keep a
     /// copy of the pointer to the map when created so creating new map
     /// won't interrupt existing maps. When readers found EOF, they should
     /// clone this pointer and read from the new map.
     map: ArcSwap<SharedMap>,
+
+    /// The error that terminated the background task, if any. Once set, it's
+    /// never cleared.
+    failure: ArcSwapOption<ErrorType>,
+
+    /// Maps of all segments in use, including the active one. Readers moving
+    /// to another segment look it up here first so that a segment is mapped
+    /// only once.
+    segments: Mutex<BTreeMap<String, Weak<SharedMap>>>,
+
+    /// Held while archiving so that concurrent runs don't interleave.
+    archiving: Mutex<()>,
+
+    /// UUID of the last log written. Only maintained by the background task
+    /// when the ordering policy is not [`OrderingPolicy::Allow`].
+    last_uuid: Mutex<Uuid>,
+
+    /// Keys to encrypt and decrypt bodies, initialized from the configuration
+    /// and replaced when rotated.
+    keyring: ArcSwap<Keyring>,
+
+    /// Logs before this UUID are truncated, but may still be in the segment
+    /// containing it. See [`truncate`].
+    start: ArcSwap<Uuid>,
+
+    /// Truncations sent to the appender, which applies them in order with
+    /// logs.
+    truncations: mpsc::UnboundedSender<Truncation>,
+
+    /// If the topic is written by another process, see [`refresh`]. Writes
+    /// and truncations fail with [`ErrorType::ReadOnly`].
+    ///
+    /// [`refresh`]: Shared::refresh
+    read_only: bool,
 }
 
 impl Shared {
-    pub fn new(conf: TopicBuilder, map: Arc<SharedMap>) -> Self {
+    pub fn new(
+        conf: TopicBuilder,
+        map: Arc<SharedMap>,
+        last_uuid: Uuid,
+        start: Uuid,
+        truncations: mpsc::UnboundedSender<Truncation>,
+    ) -> Self {
+        let segments = BTreeMap::from([(map.name().to_owned(), Arc::downgrade(&map))]);
+
         Self {
-            conf,
             event: Event::new(),
             stop: Notify::new(),
             map: ArcSwap::from(map),
+            failure: ArcSwapOption::empty(),
+            segments: Mutex::new(segments),
+            archiving: Mutex::new(()),
+            last_uuid: Mutex::new(last_uuid),
+            keyring: ArcSwap::from_pointee(conf.keyring.clone()),
+            start: ArcSwap::from_pointee(start),
+            truncations,
+            read_only: false,
+            conf,
+        }
+    }
+
+    /// Returns a read-only topic, whose active map is the newest segment
+    /// written by another process.
+    pub fn read_only(conf: TopicBuilder, map: Arc<SharedMap>, start: Uuid) -> Self {
+        Self {
+            read_only: true,
+            ..Self::new(conf, map, Uuid::NIL, start, mpsc::unbounded_channel().0)
+        }
+    }
+
+    /// Send `log` to the appender and return the UUID it's written with. Logs
+    /// with headers are rejected if the topic doesn't store them.
+    ///
+    /// Unless the ordering policy is [`OrderingPolicy::Allow`], waits until
+    /// the appender checked the order of the log, see [`Shared::order`].
+    /// Replicated logs are always waited for, so that the follower resumes
+    /// after the last one written.
+    pub async fn send(&self, send: &AsyncSender<Queued>, log: Log, origin: Origin) -> Result<Uuid> {
+        if self.read_only {
+            return Err(ErrorType::ReadOnly);
+        }
+        if !log.headers.is_empty() && !self.conf.record_format().headers {
+            return Err(ErrorType::HeadersDisabled);
+        }
+
+        let uuid = log.uuid;
+        let (ack, acked) = match (self.conf.ordering_policy, origin) {
+            (OrderingPolicy::Allow, Origin::Given | Origin::Generated) => (None, None),
+            _ => oneshot::channel().pipe(|(ack, acked)| (Some(ack), Some(acked))),
+        };
+
+        let queued = Queued { log, origin, ack };
+        send.send(queued).await.map_err(|e| self.send_error(e))?;
+
+        match acked {
+            Some(acked) => acked.await.map_err(|_| self.stopped())?,
+            None => Ok(uuid),
+        }
+    }
+
+    /// Keep `queued` after `last` according to the ordering policy, by giving
+    /// it a new UUID or returning `None` to reject it. `last` is moved to the
+    /// UUID of the log returned.
+    ///
+    /// UUIDs generated by writers are replaced instead of rejected, since
+    /// they're only in order within a thread.
+    fn order(&self, last: &mut Uuid, mut queued: Queued) -> Option<Queued> {
+        let uuid = queued.log.uuid;
+        match (self.conf.ordering_policy, queued.origin) {
+            (OrderingPolicy::Allow, _) => return Some(queued),
+            _ if uuid > *last => {}
+            // The follower skips logs it already has, see `Follower::replicate`
+            (_, Origin::Replicated) => {}
+            (OrderingPolicy::Reject, Origin::Given) => {
+                trace!(%uuid, last = %last, "Rejecting out of order log");
+                queued.reject(ErrorType::OutOfOrder { uuid, last: *last });
+                return None;
+            }
+            _ => queued.log.uuid = uuid_after(*last),
+        }
+
+        *last = queued.log.uuid;
+        Some(queued)
+    }
+
+    /// Send `truncate` to the appender and wait until it's applied. Logs sent
+    /// before are written first.
+    pub async fn truncate(&self, truncate: Truncate) -> Result<()> {
+        if self.read_only {
+            return Err(ErrorType::ReadOnly);
+        }
+
+        let (done, applied) = oneshot::channel();
+        self.truncations
+            .send(Truncation { truncate, done })
+            .map_err(|_| self.stopped())?;
+        applied.await.map_err(|_| self.stopped())??;
+
+        Ok(())
+    }
+
+    /// Apply `truncate` to finished segments. Called by the background task on
+    /// a blocking thread, once the segments logs sent before it are written
+    /// to are finished.
+    /// Logs written after are kept in order with the last one kept.
+    pub fn apply_truncation(&self, truncate: Truncate, dedup: Option<&mut Dedup>) -> Result<()> {
+        // Don't upload segments being changed
+        let _guard = self.archiving.lock().unwrap();
+
+        let dir = self.conf.topic_dir();
+        let archive = self.conf.archive.backend();
+
+        // Archived copies of the segments are removed too, so check it's possible
+        // before anything is changed
+        if archive.map_or(false, |backend| !backend.can_remove()) {
+            return Err(std::io::Error::from(ErrorKind::Unsupported).into());
+        }
+
+        // A plan left by a failed truncation is finished before it's replaced
+        truncate::replay(&dir, archive)?;
+
+        let active = self.map();
+        let names = self
+            .segment_names()?
+            .tap_mut(|names| names.retain(|name| name != active.name()));
+        let maps = |name: &String| self.segment(name).transpose();
+
+        match truncate {
+            Truncate::After(uuid) => {
+                let (plan, kept) = Plan::after(names.iter().rev().filter_map(maps), uuid)?;
+                trace!(?plan, "Truncating after {uuid}");
+
+                plan.save(&dir)?;
+                self.unmap(&plan)?;
+                plan.apply(&dir, archive)?;
+                Plan::done(&dir)?;
+
+                if let Some(dedup) = dedup {
+                    dedup.retain(|u| u <= uuid);
+                }
+
+                *self.last_uuid.lock().unwrap() = kept.unwrap_or(Uuid::NIL);
+            }
+            Truncate::Before(uuid) => {
+                trace!("Truncating before {uuid}");
+
+                truncate::save_start(&dir, uuid)?;
+                self.start.store(Arc::new(uuid));
+
+                let plan = Plan::before(names.iter().filter_map(maps), uuid)?;
+                self.unmap(&plan)?;
+                plan.apply(&dir, archive)?;
+
+                // New logs must not be hidden by the start
+                let mut last = self.last_uuid.lock().unwrap();
+                *last = (*last).max(uuid_before(uuid));
+            }
         }
+
+        Ok(())
+    }
+
+    /// Make readers of logs dropped by `plan` fail with
+    /// [`ErrorType::Truncated`].
+    fn unmap(&self, plan: &Plan) -> Result<()> {
+        let mut segments = self.segments.lock().unwrap();
+
+        for name in &plan.remove {
+            if let Some(map) = segments.remove(name).and_then(|map| map.upgrade()) {
+                map.remove();
+            }
+        }
+
+        if let Some(cut) = &plan.cut {
+            if let Some(map) = segments.get(&cut.segment).and_then(Weak::upgrade) {
+                map.truncate(cut.end as _)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fails with [`ErrorType::Truncated`] if the log with `uuid` at `at` of
+    /// `map`, which is decoded already, was truncated since.
+    pub fn check_truncated(&self, map: &SharedMap, at: usize, uuid: Uuid) -> Result<()> {
+        if map.is_removed() || at >= map.offset() || uuid < **self.start.load() {
+            return Err(ErrorType::Truncated);
+        }
+        Ok(())
+    }
+
+    /// The error a request to the background task fails with if it's stopped
+    fn stopped(&self) -> ErrorType {
+        self.failure()
+            .map_or(ErrorType::Shutdown, ErrorType::Background)
+    }
+
+    /// Returns the key new segments should be encrypted with.
+    pub fn active_key(&self) -> Option<EncryptionKey> {
+        self.keyring.load().active().cloned()
+    }
+
+    pub fn active_key_id(&self) -> Option<u8> {
+        self.keyring.load().active().map(EncryptionKey::id)
+    }
+
+    #[cfg(feature = "encryption")]
+    pub fn rotate_key(&self, key: &EncryptionKey) {
+        self.keyring
+            .rcu(|keyring| Keyring::clone(keyring).tap_mut(|keyring| keyring.insert(key.clone())));
+    }
+
+    /// Decrypt and decompress the body of `log` read at `offset` of `segment`,
+    /// according to the format of the segment.
+    pub fn decode_body(
+        &self,
+        segment: &str,
+        offset: usize,
+        format: RecordFormat,
+        log: Log,
+    ) -> Result<Log> {
+        let log = match format.key_id {
+            Some(id) => self.keyring.load().get(id)?.decrypt(segment, offset, log)?,
+            None => log,
+        };
+
+        format.compression.decompress(log)
+    }
+
+    /// Record the error that terminated the background task and wake up all
+    /// pending readers so they can observe it.
+    pub fn fail(&self, err: ErrorType) -> Arc<ErrorType> {
+        let err = Arc::new(err);
+        self.failure.store(Some(err.clone()));
+        self.event.notify_additional(usize::MAX);
+        err
+    }
+
+    pub fn failure(&self) -> Option<Arc<ErrorType>> {
+        self.failure.load_full()
+    }
+
+    /// Convert an error from the closed channel to the error that actually
+    /// caused the background task to stop, if there's one.
+    pub fn send_error(&self, err: kanal::SendError) -> ErrorType {
+        self.failure()
+            .map_or_else(|| err.into(), ErrorType::Background)
     }
 
     pub fn swap_map(&self, map: Arc<SharedMap>) -> Arc<SharedMap> {
+        self.segments
+            .lock()
+            .unwrap()
+            .tap_mut(|segments| segments.retain(|_, map| map.strong_count() > 0))
+            .insert(map.name().to_owned(), Arc::downgrade(&map));
+
         self.map.swap(map)
     }
 
+    /// Names of all segments, local or archived, sorted from oldest to newest.
+    pub fn segment_names(&self) -> Result<Vec<String>> {
+        let mut names = list_segments(&self.conf.topic_dir())?;
+
+        if let Some(backend) = self.conf.archive.backend() {
+            names.extend(backend.list()?);
+            names.sort_unstable();
+            names.dedup();
+        }
+
+        Ok(names)
+    }
+
+    /// Returns the map of segment `name`. If it's not in use, open it from
+    /// the topic directory, downloading from the archive if it's not there.
+    /// Returns `None` if the segment doesn't exist, or is being created by
+    /// the writer and not in place yet.
+    // Hold the lock while opening so that a segment is only mapped (and downloaded) once
+    #[allow(clippy::significant_drop_tightening)]
+    pub fn segment(&self, name: &str) -> Result<Option<Arc<SharedMap>>> {
+        let mut segments = self.segments.lock().unwrap();
+
+        if let Some(map) = segments.get(name).and_then(Weak::upgrade) {
+            return Ok(Some(map));
+        }
+
+        // The writer locks a new segment when it's created, and adds it here before
+        // it becomes active. Opening it meanwhile would wait for the lock forever.
+        if !self.read_only && name > self.map().name() {
+            return Ok(None);
+        }
+
+        let dir = self.conf.topic_dir();
+        let path = dir.join(name).with_extension(SegmentFile::Log.extension());
+
+        if !path.exists() {
+            let Some(backend) = self.conf.archive.backend() else { return Ok(None) };
+
+            trace!(name, "Downloading archived segment");
+
+            let idx = dir
+                .join(name)
+                .with_extension(SegmentFile::Index.extension());
+            if !backend.download(name, SegmentFile::Index, &idx)?
+                || !backend.download(name, SegmentFile::Log, &path)?
+            {
+                return Ok(None);
+            }
+        }
+
+        let map = if self.read_only {
+            // Only the newest segment may still be written
+            let map = SharedMap::open_live(&dir, name, self.conf.log_size, self.conf.index_size)?;
+            map.refresh(name < self.map().name())?;
+            map
+        } else {
+            SharedMap::open(&dir, name)?
+        }
+        .pipe(Arc::new);
+        segments.insert(name.to_owned(), Arc::downgrade(&map));
+
+        Ok(Some(map))
+    }
+
+    /// Returns the map of the segment after `name`, if there's one.
+    pub fn next_segment(&self, name: &str) -> Result<Option<Arc<SharedMap>>> {
+        let Some(next) = self.segment_names()?.into_iter().find(|n| n.as_str() > name) else {
+            return Ok(None)
+        };
+
+        self.segment(&next)
+    }
+
+    /// Same as [`next_segment`](Shared::next_segment), on a blocking thread
+    /// since archived segments may be downloaded. See [`joined`].
+    pub fn spawn_next_segment(self: &Arc<Self>, name: &str) -> Lookup {
+        let (this, name) = (self.clone(), name.to_owned());
+        tokio::task::spawn_blocking(move || this.next_segment(&name))
+    }
+
+    /// Returns the map of the segment which may contain `uuid`, i.e. the last
+    /// one created before `uuid`, or the first one if there's none.
+    pub fn find_segment(&self, uuid: Uuid) -> Result<Option<Arc<SharedMap>>> {
+        let names = self.segment_names()?;
+        let target = uuid.encode();
+        let at = names
+            .partition_point(|n| n.as_str() <= target.as_str())
+            .saturating_sub(1);
+
+        names.get(at).map_or(Ok(None), |name| self.segment(name))
+    }
+
+    /// Returns the position of the first log whose UUID is not less than
+    /// `uuid`, or the end of the active segment if there's none. See
+    /// [`SharedMap::seek`].
+    pub fn seek(&self, uuid: Uuid) -> Result<(Arc<SharedMap>, usize, usize)> {
+        let uuid = uuid.max(**self.start.load());
+        let mut map = self.find_segment(uuid)?.unwrap_or_else(|| self.map());
+
+        loop {
+            let finished = map.is_finished();
+            let (read_at, skip) = map.seek(uuid)?;
+
+            // Segments are named when created, which can be later than the UUIDs of their
+            // first logs queued before, so the log may be in the following segments.
+            if read_at < map.offset() || !finished {
+                return Ok((map, read_at, skip));
+            }
+
+            match self.next_segment(map.name())? {
+                Some(next) => map = next,
+                None => return Ok((map, read_at, skip)),
+            }
+        }
+    }
+
+    /// UUID of the last log written, searching from the newest segment.
+    pub fn newest_uuid(&self) -> Result<Option<Uuid>> {
+        for name in self.segment_names()?.iter().rev() {
+            let Some(map) = self.segment(name)? else { continue };
+            if let Some(last) = map.last_uuid()? {
+                return Ok(Some(last));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Catch up with the writer of a read-only topic. Open the newest segment
+    /// if the writer rolled, reload the start of the topic and the committed
+    /// length of segments in use, and wake up readers if any changed.
+    pub fn refresh(&self) -> Result<()> {
+        debug_assert!(self.read_only);
+
+        let start = truncate::load_start(&self.conf.topic_dir())?;
+        let mut changed = *self.start.swap(Arc::new(start)) != start;
+
+        let names = self.segment_names()?;
+        let active = self.map();
+        if let Some(newest) = names.last().filter(|name| name.as_str() > active.name()) {
+            match self.segment(newest) {
+                Ok(Some(map)) => {
+                    trace!(name = newest, "Writer rolled");
+                    self.swap_map(map);
+                    changed = true;
+                }
+                // Not created yet, try again next time
+                Ok(None) => {}
+                Err(ErrorType::Io(e)) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        let active = self.map();
+        let maps = self
+            .segments
+            .lock()
+            .unwrap()
+            .values()
+            .filter_map(Weak::upgrade)
+            .collect::<Vec<_>>();
+        for map in maps {
+            if names
+                .binary_search_by(|name| name.as_str().cmp(map.name()))
+                .is_err()
+            {
+                changed |= !map.is_removed();
+                map.remove();
+            } else {
+                changed |= map.refresh(map.name() < active.name())?;
+            }
+        }
+
+        if changed {
+            self.event.notify_additional(usize::MAX);
+        }
+
+        Ok(())
+    }
+
+    /// [`refresh`](Shared::refresh) every poll interval until stopped.
+    pub async fn watch(&self) -> Result<()> {
+        let mut interval = interval(self.conf.poll_interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            select! {
+                _ = interval.tick() => self.refresh()?,
+                _ = self.stop.notified() => return Err(ErrorType::Shutdown),
+            }
+        }
+    }
+
+    /// Upload finished segments that are not archived yet, then remove
+    /// archived segments beyond local retention from the topic directory.
+    pub fn archive(&self) -> Result<()> {
+        let Some(backend) = self.conf.archive.backend() else { return Ok(()) };
+        let _guard = self.archiving.lock().unwrap();
+
+        let dir = self.conf.topic_dir();
+        let active = self.map();
+        let mut archived = backend.list()?.into_iter().collect::<BTreeSet<_>>();
+        let finished = list_segments(&dir)?
+            .into_iter()
+            .filter(|name| name != active.name())
+            .collect::<Vec<_>>();
+
+        for name in &finished {
+            if !archived.contains(name) && self.upload(backend, name)? {
+                archived.insert(name.clone());
+            }
+        }
+
+        let Some(retention) = self.conf.local_retention else { return Ok(()) };
+
+        // Only remove segments that are safely archived
+        finished
+            .iter()
+            .rev()
+            .skip(retention)
+            .filter(|name| archived.contains(*name))
+            .for_each(|name| self.remove_local(name));
+
+        Ok(())
+    }
+
+    /// Upload segment `name`, returns `false` if it doesn't exist.
+    fn upload(&self, backend: &dyn ArchiveBackend, name: &str) -> Result<bool> {
+        let Some(map) = self.segment(name)? else { return Ok(false) };
+
+        trace!(name, "Archiving segment");
+
+        // Only upload committed bytes, the files may not be truncated yet
+        let index = map.index()?;
+        let header = index.header().as_bytes();
+        backend.upload(name, SegmentFile::Index, &mut header.chain(&*index.slice()))?;
+
+        let header = map.header().as_bytes();
+        backend.upload(name, SegmentFile::Log, &mut header.chain(&*map.slice(0)))?;
+
+        Ok(true)
+    }
+
+    fn remove_local(&self, name: &str) {
+        trace!(name, "Removing archived segment");
+
+        let path = self.conf.topic_dir().join(name);
+        for file in [SegmentFile::Log, SegmentFile::Index] {
+            if let Err(e) = fs::remove_file(path.with_extension(file.extension())) {
+                warn!(name, error = %e, "Failed to remove archived segment");
+            }
+        }
+    }
+
     pub fn map(&self) -> Arc<SharedMap> {
         self.map.load_full()
     }
@@ -63,27 +619,199 @@ impl Shared {
     }
 }
 
+/// Logs decoded from a segment that are not consumed yet, with their offsets
+pub type Pending = VecDeque<(usize, Log)>;
+
 /// Shared map for reading concurrently and writing exclusively
 #[derive(Debug)]
 pub struct SharedMap {
+    dir: PathBuf,
+    name: String,
     map: RawMap,
     offset: AtomicUsize,
     finished: AtomicBool,
+    /// Set when the segment is removed by truncation
+    removed: AtomicBool,
+    /// Index of the segment. Set when created by the writer, or opened lazily
+    /// for finished segments.
+    index: ArcSwapOption<IndexMap>,
+    /// Size the index is mapped with if the segment is written by another
+    /// process, see [`open_live`](SharedMap::open_live)
+    index_size: Option<u64>,
+    /// Format of records, fixed for the lifetime of the segment
+    format: RecordFormat,
 }
 
 impl SharedMap {
-    pub fn new(dir: &Path, name: &str, size: u64) -> Result<Self> {
-        let map = RawMap::new(&dir.join(name).with_extension("limlog"), size, Header::LOG)?;
+    pub fn new(
+        dir: &Path,
+        name: &str,
+        size: u64,
+        chunk: Option<u64>,
+        format: RecordFormat,
+    ) -> Result<Self> {
+        let path = dir.join(name).with_extension("limlog");
+        let header = Header::LOG.tap_mut(|h| h.set_attributes(format.attributes()));
+        let map = RawMap::new(&path, size, chunk, header)?;
         let offset = AtomicUsize::new(0);
         let finished = AtomicBool::new(false);
 
         Ok(Self {
+            dir: dir.to_owned(),
+            name: name.to_owned(),
+            map,
+            offset,
+            finished,
+            removed: AtomicBool::new(false),
+            index: ArcSwapOption::empty(),
+            index_size: None,
+            format,
+        })
+    }
+
+    /// Open a finished segment read-only.
+    pub fn open(dir: &Path, name: &str) -> Result<Self> {
+        let map = RawMap::open(&dir.join(name).with_extension("limlog"), Header::LOG)?;
+        let offset = AtomicUsize::new(map.committed());
+        let finished = AtomicBool::new(true);
+        let format = RecordFormat::from_attributes(map.load_header().attributes());
+
+        Ok(Self {
+            dir: dir.to_owned(),
+            name: name.to_owned(),
             map,
             offset,
             finished,
+            removed: AtomicBool::new(false),
+            index: ArcSwapOption::empty(),
+            index_size: None,
+            format,
+        })
+    }
+
+    /// Open a segment written by another process read-only, mapping
+    /// `log_size` and `index_size` like the writer. It's not finished until
+    /// [`refresh`](SharedMap::refresh) says so.
+    pub fn open_live(dir: &Path, name: &str, log_size: u64, index_size: u64) -> Result<Self> {
+        let path = dir.join(name).with_extension("limlog");
+        let map = RawMap::open_live(&path, log_size, Header::LOG)?;
+        let offset = AtomicUsize::new(map.committed().min(map.mapped_len()));
+        let format = RecordFormat::from_attributes(map.load_header().attributes());
+
+        Ok(Self {
+            dir: dir.to_owned(),
+            name: name.to_owned(),
+            map,
+            offset,
+            finished: AtomicBool::new(false),
+            removed: AtomicBool::new(false),
+            index: ArcSwapOption::empty(),
+            index_size: Some(index_size),
+            format,
         })
     }
 
+    /// Format of records in the segment
+    pub const fn format(&self) -> RecordFormat {
+        self.format
+    }
+
+    pub fn set_index(&self, index: Arc<IndexMap>) {
+        self.index.store(Some(index));
+    }
+
+    /// Returns the index of the segment, opening it if it's not opened yet.
+    pub fn index(&self) -> Result<Arc<IndexMap>> {
+        if let Some(index) = self.index.load_full() {
+            return Ok(index);
+        }
+
+        let index = match self.index_size {
+            Some(size) => IndexMap::open_live(&self.dir, &self.name, size)?,
+            None => IndexMap::open(&self.dir, &self.name)?,
+        }
+        .pipe(Arc::new);
+        self.set_index(index.clone());
+
+        Ok(index)
+    }
+
+    /// Decode the records at `read_at`, see [`RecordFormat::decode_batch`].
+    /// Returns them with their offsets in the segment, and the offset after
+    /// them.
+    pub fn decode(&self, read_at: usize) -> bincode::Result<Option<(Pending, usize)>> {
+        let Some((records, len)) = self.format.decode_batch(&self.slice(read_at))? else {
+            return Ok(None)
+        };
+
+        let records = records
+            .into_iter()
+            .map(|(at, log)| (read_at + at as usize, log))
+            .collect();
+
+        Ok(Some((records, read_at + len as usize)))
+    }
+
+    /// UUIDs of all committed logs, in order.
+    pub fn uuids(&self) -> Result<Vec<Uuid>> {
+        let mut uuids = vec![];
+        let mut read_at = 0;
+
+        while let Some((records, next)) = self.decode(read_at)? {
+            uuids.extend(records.into_iter().map(|(_, log)| log.uuid));
+            read_at = next;
+        }
+
+        Ok(uuids)
+    }
+
+    /// UUID of the last committed log, if any.
+    pub fn last_uuid(&self) -> Result<Option<Uuid>> {
+        let mut read_at = self.index().map_or(0, |index| index.lower_bound(Uuid::MAX));
+        let mut last = None;
+
+        while let Some((mut records, next)) = self.decode(read_at)? {
+            last = records.pop_back().map(|(_, log)| log.uuid);
+            read_at = next;
+        }
+
+        Ok(last)
+    }
+
+    /// Returns the position of the first log whose UUID is not less than
+    /// `uuid`, or the end of the segment if there's none. The position is the
+    /// offset of the records it's decoded with, and the number of logs before
+    /// it in them, which is always `0` in [`FormatVersion::V1`].
+    ///
+    /// [`FormatVersion::V1`]: crate::formats::FormatVersion::V1
+    pub fn seek(&self, uuid: Uuid) -> Result<(usize, usize)> {
+        let mut read_at = match self.index() {
+            Ok(index) => index.lower_bound(uuid),
+            Err(e) => {
+                warn!(name = self.name, error = %e, "Failed to open index, scanning from start");
+                0
+            }
+        };
+
+        while let Some((records, next)) = self.decode(read_at)? {
+            if let Some(skip) = records.iter().position(|(_, log)| log.uuid >= uuid) {
+                return Ok((read_at, skip));
+            }
+            read_at = next;
+        }
+
+        Ok((read_at, 0))
+    }
+
+    /// Name of the segment, which is also the file stem
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn header(&self) -> Header {
+        self.map.load_header()
+    }
+
     /// Load the offset with [`Ordering::Acquire`]
     #[inline(always)]
     pub fn offset(&self) -> usize {
@@ -102,10 +830,10 @@ impl SharedMap {
     #[allow(clippy::mut_from_ref)]
     pub unsafe fn mut_slice(&self) -> &mut [u8] {
         let at = self.offset();
-        debug_assert!(at <= self.map.len());
-        let len = self.map.len() - at;
+        let mapped = self.map.mapped_len();
+        debug_assert!(at <= mapped);
 
-        std::slice::from_raw_parts_mut(self.map.as_mut_ptr().add(at), len)
+        self.map.range_mut(at, mapped - at)
     }
 
     /// Get the slice of the map from the given offset
@@ -114,7 +842,7 @@ impl SharedMap {
     ///
     /// Panics if `from` is greater than the current offset
     #[inline]
-    pub fn slice(&self, from: usize) -> &[u8] {
+    pub fn slice(&self, from: usize) -> MapSlice<'_> {
         let at = self.offset();
         let from = from.min(at);
 
@@ -122,12 +850,33 @@ impl SharedMap {
         unsafe { self.map.range(from, at - from) }
     }
 
+    /// Make sure the next `len` bytes are backed by the file before writing
+    /// to them.
+    pub fn reserve(&self, len: usize) -> Result<()> {
+        self.map.reserve(self.offset() + len)
+    }
+
     pub fn commit(&self, len: usize) -> Result<()> {
-        self.map.flush_range(self.offset(), len)?;
+        let offset = self.offset();
+        self.map.flush_range(offset, len)?;
         self.offset.fetch_add(len, Ordering::AcqRel);
         Ok(())
     }
 
+    /// Advance the watermark in the headers of the segment and its index past
+    /// the logs committed so far, once they reach the disk. Only the writer may
+    /// call this.
+    pub fn sync(&self) -> Result<()> {
+        self.map.sync(self.offset())?;
+
+        // Entries past the watermark of the log are dropped by repair
+        if let Some(index) = self.index.load_full() {
+            index.sync()?;
+        }
+
+        Ok(())
+    }
+
     #[inline]
     pub fn remaining(&self) -> usize {
         self.map.len() - self.offset_relaxed()
@@ -136,9 +885,7 @@ impl SharedMap {
     #[inline]
     pub fn finish(&self) -> Result<()> {
         self.finished.store(true, Ordering::Release);
-        self.map.flush_sync()?;
-
-        Ok(())
+        self.sync()
     }
 
     #[inline]
@@ -146,6 +893,166 @@ impl SharedMap {
         // Readers should see the file is finished after writer marks it so
         self.finished.load(Ordering::Acquire)
     }
+
+    /// Drop logs at or after `end` of a finished segment, along with their
+    /// index entries. Only the committed length is lowered, data is kept
+    /// mapped for readers that are decoding it.
+    pub fn truncate(&self, end: usize) -> Result<()> {
+        debug_assert!(self.is_finished());
+
+        self.offset.fetch_min(end, Ordering::AcqRel);
+        self.index()?.truncate(end);
+
+        Ok(())
+    }
+
+    /// Load the committed length of a segment opened with
+    /// [`open_live`](SharedMap::open_live), which is lowered if it's cut by
+    /// truncation, and mark it `finished` once the writer moved on. Returns if
+    /// anything changed.
+    pub fn refresh(&self, finished: bool) -> Result<bool> {
+        let committed = self.map.committed();
+        // Logs before the watermark are written before it
+        fence(Ordering::Acquire);
+        // The file grows as it's written, map it again to cover the logs
+        let committed = committed.min(self.map.remap(committed)?);
+
+        if let Some(index) = self.index.load_full() {
+            index.refresh()?;
+        }
+
+        let changed = self.offset.swap(committed, Ordering::AcqRel) != committed;
+        Ok(changed | (finished && !self.finished.swap(true, Ordering::AcqRel)))
+    }
+
+    /// Mark the segment as removed by truncation.
+    pub fn remove(&self) {
+        self.removed.store(true, Ordering::Release);
+    }
+
+    pub fn is_removed(&self) -> bool {
+        self.removed.load(Ordering::Acquire)
+    }
+
+    /// Fails with [`ErrorType::Truncated`] if reading from `read_at` would
+    /// skip truncated logs, i.e. the segment is removed or cut before it.
+    pub fn check(&self, read_at: usize) -> Result<()> {
+        if self.is_removed() || read_at > self.offset() {
+            return Err(ErrorType::Truncated);
+        }
+        Ok(())
+    }
+}
+
+/// Where the UUID of a [`Queued`] log comes from, which decides what the
+/// ordering policy does with it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    /// Given by the caller, e.g. with
+    /// [`Writer::write_one`](crate::Writer::write_one)
+    Given,
+    /// Generated by the writer
+    Generated,
+    /// Copied from a leader by a [`Follower`](crate::Follower), kept as is
+    /// whatever the ordering policy
+    Replicated,
+}
+
+/// Receives the UUID a [`Queued`] log is written with, or why it's rejected.
+/// Only set if the ordering policy is not [`OrderingPolicy::Allow`].
+type Ack = Option<oneshot::Sender<Result<Uuid>>>;
+
+/// A log sent to the appender.
+#[derive(Debug)]
+pub struct Queued {
+    pub log: Log,
+    pub origin: Origin,
+    ack: Ack,
+}
+
+impl Queued {
+    /// Acknowledge the log as is, e.g. when it's dropped as a duplicate.
+    fn acked(self) {
+        ack(self.ack, Ok(self.log.uuid));
+    }
+
+    fn reject(self, err: ErrorType) {
+        ack(self.ack, Err(err));
+    }
+}
+
+/// Send `res` to the writer waiting for it, if any. The writer may be gone.
+fn ack(ack: Ack, res: Result<Uuid>) {
+    if let Some(ack) = ack {
+        ack.send(res).ok();
+    }
+}
+
+/// Segment lookup running on a blocking thread, see
+/// [`Shared::spawn_next_segment`].
+pub type Lookup = JoinHandle<Result<Option<Arc<SharedMap>>>>;
+
+/// Returns the result of a task spawned on a blocking thread. Panics are
+/// resumed, and tasks are only cancelled when the runtime shuts down.
+pub fn joined<T>(res: std::result::Result<Result<T>, JoinError>) -> Result<T> {
+    match res {
+        Ok(res) => res,
+        Err(e) if e.is_panic() => resume_unwind(e.into_panic()),
+        Err(_) => Err(ErrorType::Shutdown),
+    }
+}
+
+/// Returns a new UUID which is greater than `last`, even if the clock is
+/// behind it.
+pub fn uuid_after(last: Uuid) -> Uuid {
+    let uuid = uuid7();
+    if uuid > last {
+        return uuid;
+    }
+
+    (u128::from_be_bytes(*last.as_bytes()) + 1)
+        .to_be_bytes()
+        .pipe(Uuid::from)
+}
+
+/// Returns the UUID right before `uuid`, so that UUIDs after it are not less
+/// than `uuid`.
+fn uuid_before(uuid: Uuid) -> Uuid {
+    u128::from_be_bytes(*uuid.as_bytes())
+        .saturating_sub(1)
+        .to_be_bytes()
+        .pipe(Uuid::from)
+}
+
+/// UUID of the last log in `dir`, searching from the newest segment.
+pub fn last_uuid(dir: &Path) -> Result<Option<Uuid>> {
+    for name in list_segments(dir)?.iter().rev() {
+        if let Some(last) = SharedMap::open(dir, name)?.last_uuid()? {
+            return Ok(Some(last));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Names of segments in `dir`, sorted from oldest to newest.
+pub fn list_segments(dir: &Path) -> Result<Vec<String>> {
+    let mut names = vec![];
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(OsStr::to_str) != Some(SegmentFile::Log.extension()) {
+            continue;
+        }
+        match path.file_stem().and_then(OsStr::to_str) {
+            Some(stem) if stem.parse::<Uuid>().is_ok() => names.push(stem.to_owned()),
+            _ => {}
+        }
+    }
+
+    names.sort_unstable();
+
+    Ok(names)
 }
 
 impl Drop for SharedMap {
@@ -154,108 +1061,569 @@ impl Drop for SharedMap {
     }
 }
 
-/// Index map for read and write exclusively
+/// Index map for reading concurrently and writing exclusively
 #[derive(Debug)]
-pub struct UniqueMap {
+pub struct IndexMap {
     map: RawMap,
-    pos: usize,
+    /// Length of committed entries in bytes
+    len: AtomicUsize,
 }
 
-impl UniqueMap {
-    pub fn new(dir: &Path, name: &str, size: u64) -> Result<Self> {
-        let map = RawMap::new(&dir.join(name).with_extension("idx"), size, Header::INDEX)?;
+impl IndexMap {
+    pub fn new(dir: &Path, name: &str, size: u64, chunk: Option<u64>) -> Result<Self> {
+        let path = dir.join(name).with_extension("idx");
+        let map = RawMap::new(&path, size, chunk, Header::INDEX)?;
+
+        Ok(Self {
+            map,
+            len: AtomicUsize::new(0),
+        })
+    }
+
+    /// Mark the index as sparse, i.e. not every log is indexed.
+    pub fn mark_sparse(&self) {
+        self.map.update_header(|h| {
+            h.set_attributes(h.attributes().tap_mut(|a| a.flags |= Attributes::SPARSE));
+        });
+    }
+
+    /// Open the index of a finished segment read-only.
+    pub fn open(dir: &Path, name: &str) -> Result<Self> {
+        let map = RawMap::open(&dir.join(name).with_extension("idx"), Header::INDEX)?;
+        let len = map.committed() / INDEX_SIZE * INDEX_SIZE;
 
-        Ok(Self { map, pos: 0 })
+        Ok(Self {
+            map,
+            len: AtomicUsize::new(len),
+        })
+    }
+
+    /// Open the index of a segment written by another process read-only, see
+    /// [`SharedMap::open_live`].
+    pub fn open_live(dir: &Path, name: &str, size: u64) -> Result<Self> {
+        let map = RawMap::open_live(&dir.join(name).with_extension("idx"), size, Header::INDEX)?;
+
+        Ok(Self {
+            len: AtomicUsize::new(map.committed().min(map.mapped_len()) / INDEX_SIZE * INDEX_SIZE),
+            map,
+        })
+    }
+
+    /// Load the committed length of an index opened with
+    /// [`open_live`](IndexMap::open_live).
+    pub fn refresh(&self) -> Result<()> {
+        let len = self.map.committed();
+        let len = len.min(self.map.remap(len)?) / INDEX_SIZE * INDEX_SIZE;
+        self.len.store(len, Ordering::Release);
+        Ok(())
     }
 
     /// If the index file is full. Returns true if it cannot handle one more
     /// [`UuidIndex`]
     pub fn is_full(&self) -> bool {
-        self.pos + INDEX_SIZE > self.map.len()
+        self.len.load(Ordering::Relaxed) + INDEX_SIZE > self.map.len()
     }
 
+    /// # Safety
+    /// Caller must guarantee that this is exclusive
     #[allow(clippy::missing_panics_doc)]
-    pub fn push(&mut self, index: UuidIndex) -> Result<()> {
+    pub unsafe fn push(&self, index: UuidIndex) -> Result<()> {
         debug_assert!(!self.is_full());
 
-        // SAFETY: self is a mutable reference
-        let slice = unsafe { self.map.range_mut(self.pos, INDEX_SIZE) };
-        index.write_to(slice.try_into().unwrap());
-        self.map.flush_range(self.pos, INDEX_SIZE)?;
-        self.pos += INDEX_SIZE;
+        let pos = self.len.load(Ordering::Acquire);
+        self.map.reserve(pos + INDEX_SIZE)?;
+        index.write_to(self.map.range_mut(pos, INDEX_SIZE).try_into().unwrap());
+        self.map.flush_range(pos, INDEX_SIZE)?;
+        self.len.fetch_add(INDEX_SIZE, Ordering::AcqRel);
         Ok(())
     }
+
+    /// Advance the watermark past the entries pushed so far, see
+    /// [`SharedMap::sync`].
+    pub fn sync(&self) -> Result<()> {
+        self.map.sync(self.len.load(Ordering::Acquire))
+    }
+
+    /// Number of committed entries
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Acquire) / INDEX_SIZE
+    }
+
+    /// Get the `i`th entry
+    ///
+    /// # Panic
+    ///
+    /// Panics if `i` is not less than [`len`](IndexMap::len)
+    #[allow(clippy::missing_panics_doc)]
+    pub fn get(&self, i: usize) -> UuidIndex {
+        assert!(i < self.len());
+
+        // SAFETY: committed entries are immutable
+        let chunk = unsafe { self.map.range(i * INDEX_SIZE, INDEX_SIZE) };
+        UuidIndex::from_bytes(chunk[..].try_into().unwrap())
+    }
+
+    /// If not every log is indexed
+    pub fn is_sparse(&self) -> bool {
+        self.header().attributes().has(Attributes::SPARSE)
+    }
+
+    /// Get the committed entries as bytes
+    pub fn slice(&self) -> MapSlice<'_> {
+        // SAFETY: committed entries are immutable
+        unsafe { self.map.range(0, self.len.load(Ordering::Acquire)) }
+    }
+
+    pub fn header(&self) -> Header {
+        self.map.load_header()
+    }
+
+    /// Returns the offset to scan from for the first log whose UUID is not
+    /// less than `uuid`, i.e. offset of the last indexed log before `uuid`,
+    /// or `0` if there's none.
+    pub fn lower_bound(&self, uuid: Uuid) -> usize {
+        // Binary search, same as `slice::partition_point`
+        let (mut lo, mut hi) = (0, self.len());
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if self.get(mid).uuid < uuid {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+
+        lo.checked_sub(1).map_or(0, |i| self.get(i).offset as _)
+    }
+
+    /// Drop entries of logs at or after offset `end` of the segment.
+    pub fn truncate(&self, end: usize) {
+        let kept = (0..self.len())
+            .take_while(|&i| self.get(i).offset < end as u64)
+            .count();
+        self.len.fetch_min(kept * INDEX_SIZE, Ordering::AcqRel);
+    }
 }
 
-impl Drop for UniqueMap {
+impl Drop for IndexMap {
     fn drop(&mut self) {
-        unsafe { self.map.close(self.pos as _) }.unwrap();
+        unsafe { self.map.close(self.len.load(Ordering::Acquire) as _) }.unwrap();
     }
 }
 
 #[derive(Debug)]
 pub struct Appender {
     pub log: Arc<SharedMap>,
-    pub idx: UniqueMap,
-    pub recv: kanal::AsyncReceiver<Log>,
+    pub idx: Arc<IndexMap>,
+    /// Number of logs written to the segment
+    pub records: u64,
+    /// Number of logs written and offset of the log when the last index entry
+    /// was written
+    pub last_indexed: (u64, u64),
+    /// When the segment should be rolled regardless of its size, set on the
+    /// first write if a roll interval is configured
+    pub deadline: Option<Instant>,
+    /// When logs written since the last sync should be synced, see
+    /// [`SharedMap::sync`]
+    pub sync_at: Option<Instant>,
+    /// Recently written UUIDs, carried over to the next appender when rolled
+    pub dedup: Option<Dedup>,
+    /// Key the segment is encrypted with
+    pub key: Option<EncryptionKey>,
+    pub recv: kanal::AsyncReceiver<Queued>,
+    pub truncations: mpsc::UnboundedReceiver<Truncation>,
+    /// Truncation to apply once the segments logs queued before it are
+    /// written to are finished, carried over to the next appender when rolled
+    pub truncation: Option<Truncation>,
 }
 
 impl Appender {
-    /// Run with the given [`Log`] and return the last [`Log`] if it
-    /// cannot write it to log file due to file size.
+    /// Run with the given [`Log`]s and return those it cannot write to log file
+    /// due to file size. Also returns once a truncation is received, with logs
+    /// queued before it written, so the segment is rolled before it's applied.
     // #[instrument(level = "trace")]
-    pub async fn run(&mut self, mut rem: Option<Log>, shared: &Shared) -> Result<Option<Log>> {
-        let opt: BincodeOptions = bincode_option();
-
-        if let Some(log) = rem.take() {
-            if let Some(rem) = self.write_one(opt, log, &shared.event)? {
-                return Ok(Some(rem));
+    pub async fn run(&mut self, rem: Vec<Queued>, shared: &Shared) -> Result<Vec<Queued>> {
+        if !rem.is_empty() {
+            let rem = self.write(rem, shared)?;
+            if !rem.is_empty() || self.truncation.is_some() {
+                return Ok(rem);
             }
         }
 
+        // A receive cancelled after a writer handed it a log drops the log, so it's
+        // kept pending while logs are synced
+        let recv = self.recv.clone();
+        let mut receive = pin!(recv.recv());
+
         loop {
-            let log = select!(
-                received = self.recv.recv() => received?,
-                _ = shared.stop.notified() => return Err(ErrorType::Shutdown)
-            );
+            // If the map is full or old enough, return without any remaining log
+            if self.should_roll(&shared.conf) {
+                return Ok(vec![]);
+            }
 
-            if let Some(rem) = self.write_one(opt, log, &shared.event)? {
-                return Ok(Some(rem));
+            // Synced periodically rather than on each write, which is too slow
+            if self.sync_at.map_or(false, |at| at <= Instant::now()) {
+                self.sync()?;
             }
 
-            // If the map is full, return without any remaining log
-            if self.log.remaining() < MIN_LOG_SIZE || self.idx.is_full() {
-                return Ok(None);
+            let received = loop {
+                let deadline = self.deadline;
+                let sync_at = self.sync_at;
+                select!(
+                    received = &mut receive => break received,
+                    Some(truncation) = self.truncations.recv() => {
+                        self.truncation = Some(truncation);
+
+                        // Logs sent while it's applied are not queued before it
+                        let queued = self.recv.len();
+                        let logs = iter::from_fn(|| self.recv.try_recv().ok().flatten())
+                            .take(queued)
+                            .collect();
+                        return self.write(logs, shared);
+                    },
+                    _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                        return Ok(vec![])
+                    },
+                    _ = sleep_until(sync_at.unwrap_or_else(Instant::now)), if sync_at.is_some() => {
+                        self.sync()?;
+                    },
+                    _ = shared.stop.notified() => return Err(ErrorType::Shutdown)
+                );
+            };
+            receive.set(recv.recv());
+
+            // The topic and all writers are dropped, stop the same way as `stop`
+            let Ok(queued) = received else { return Err(ErrorType::Shutdown) };
+
+            let rem = self.write(self.batch(queued, &shared.conf), shared)?;
+            if !rem.is_empty() {
+                return Ok(rem);
+            }
+        }
+    }
+
+    /// Sync logs written since the last sync, see [`SharedMap::sync`].
+    fn sync(&mut self) -> Result<()> {
+        self.log.sync()?;
+        self.sync_at = None;
+        Ok(())
+    }
+
+    /// Take logs queued after `queued` to write them together, if the segment
+    /// is batched.
+    fn batch(&self, queued: Queued, conf: &TopicBuilder) -> Vec<Queued> {
+        let mut logs = vec![queued];
+        if self.log.format().version == FormatVersion::V1 {
+            return logs;
+        }
+
+        let max = conf
+            .max_records
+            .map_or(MAX_BATCH_SIZE, |max| max.saturating_sub(self.records))
+            .min(MAX_BATCH_SIZE);
+        while (logs.len() as u64) < max {
+            // Closed channel is reported on the next receive
+            match self.recv.try_recv() {
+                Ok(Some(queued)) => logs.push(queued),
+                _ => break,
             }
         }
+
+        logs
     }
 
-    fn write_one(&mut self, opt: BincodeOptions, log: Log, event: &Event) -> Result<Option<Log>> {
-        let len = log.byte_len();
+    fn write(&mut self, logs: Vec<Queued>, shared: &Shared) -> Result<Vec<Queued>> {
+        if self.log.format().version == FormatVersion::V2 {
+            return self.write_batch(logs, shared);
+        }
 
-        if self.log.remaining() < len || self.idx.is_full() {
-            return Ok(Some(log));
+        let mut logs = logs.into_iter();
+        while let Some(queued) = logs.next() {
+            if let Some(queued) = self.write_one(queued, shared)? {
+                return Ok(iter::once(queued).chain(logs).collect());
+            }
+        }
+
+        Ok(vec![])
+    }
+
+    fn should_roll(&self, conf: &TopicBuilder) -> bool {
+        self.log.remaining() < MIN_LOG_SIZE
+            || self.idx.is_full()
+            || conf.max_records.map_or(false, |max| self.records >= max)
+            || self.deadline.map_or(false, |d| d <= Instant::now())
+    }
+
+    /// If the log about to be written at `offset` should be indexed. The first
+    /// log of a segment is always indexed.
+    const fn should_index(&self, policy: IndexPolicy, offset: u64) -> bool {
+        let (records, indexed_at) = self.last_indexed;
+
+        match policy {
+            _ if self.records == 0 => true,
+            IndexPolicy::Dense => true,
+            IndexPolicy::Records(n) => self.records - records >= n,
+            IndexPolicy::Bytes(n) => offset - indexed_at >= n,
+        }
+    }
+
+    fn write_one(&mut self, queued: Queued, shared: &Shared) -> Result<Option<Queued>> {
+        if self
+            .dedup
+            .as_ref()
+            .map_or(false, |d| d.contains(queued.log.uuid))
+        {
+            trace!(uuid = %queued.log.uuid, "Dropping duplicated log");
+            queued.acked();
+            return Ok(None);
+        }
+        let mut last = *shared.last_uuid.lock().unwrap();
+        let Some(queued) = shared.order(&mut last, queued) else { return Ok(None) };
+
+        // The log is returned as is if it doesn't fit, so it's compressed again in the
+        // format of the next segment
+        let format = self.log.format();
+        let compressed = format.compression.compress(&queued.log)?;
+        let len = format.byte_len(compressed.as_ref().unwrap_or(&queued.log));
+
+        // Roll if the key was rotated, so the new segment is encrypted with it
+        let rotated = shared.active_key_id() != format.key_id;
+        if rotated || self.log.remaining() < len || self.idx.is_full() {
+            return Ok(Some(queued));
         }
 
         let offset = self.log.offset() as _;
+        self.log.reserve(len)?;
+
+        let Queued { log, ack: done, .. } = queued;
+        let log = compressed.unwrap_or(log);
+        let log = match &self.key {
+            Some(key) => key.encrypt(self.log.name(), offset as _, log)?,
+            None => log,
+        };
 
         {
             // SAFETY: We are the only one accessing the mutable portion of mmap
             let buf = unsafe { self.log.mut_slice() };
-            opt.serialize_into(&mut buf[..len], &log)?;
+            format.encode_into(&mut buf[..len], &log)?;
         }
 
         // Commit map. If commit failed, leave index untouched
         self.log.commit(len)?;
-        self.idx.push(UuidIndex {
-            uuid: log.uuid,
-            offset,
-        })?;
+        self.written(&[log.uuid], offset, shared)?;
+        ack(done, Ok(log.uuid));
+
+        Ok(None)
+    }
+
+    /// Write as many of `logs` as the segment can hold as a batch, and return
+    /// the rest. Logs are written one by one with [`write_one`] in
+    /// [`FormatVersion::V1`].
+    ///
+    /// [`write_one`]: Appender::write_one
+    fn write_batch(&mut self, logs: Vec<Queued>, shared: &Shared) -> Result<Vec<Queued>> {
+        let format = self.log.format();
+
+        // Same as `write_one`
+        let rotated = shared.active_key_id() != format.key_id;
+        if rotated || self.idx.is_full() {
+            return Ok(logs);
+        }
+
+        let mut uuids = HashSet::new();
+        let mut last = *shared.last_uuid.lock().unwrap();
+        let mut logs = logs
+            .into_iter()
+            .filter_map(|queued| {
+                // Retries queued together are dropped too, as they're in the window once
+                // written
+                let uuid = queued.log.uuid;
+                let duplicated = self
+                    .dedup
+                    .as_ref()
+                    .map_or(false, |d| d.contains(uuid) || !uuids.insert(uuid));
+                if duplicated {
+                    trace!(%uuid, "Dropping duplicated log");
+                    queued.acked();
+                    return None;
+                }
+                Some(queued)
+            })
+            .filter_map(|queued| shared.order(&mut last, queued));
+
+        let offset = self.log.offset();
+        let mut batch = vec![];
+        let mut acks = vec![];
+        let mut rem = vec![];
+        let mut len = BATCH_HEADER_SIZE;
+        let mut prev = None;
+        while let Some(queued) = logs.next() {
+            // Same as `write_one`, logs that don't fit are returned as is
+            let compressed = format.compression.compress(&queued.log)?;
+            let record = compressed.as_ref().unwrap_or(&queued.log);
+            let record_len = format.record_len(prev.unwrap_or(record.uuid), record);
+            if self.log.remaining() < len + record_len {
+                rem = iter::once(queued).chain(logs).collect();
+                break;
+            }
+
+            let Queued { log, ack, .. } = queued;
+            prev = Some(log.uuid);
+            acks.push(ack);
+            let record = compressed.unwrap_or(log);
+            batch.push(match &self.key {
+                Some(key) => key.encrypt(self.log.name(), offset + len, record)?,
+                None => record,
+            });
+            len += record_len;
+        }
+
+        if batch.is_empty() {
+            return Ok(rem);
+        }
+
+        self.log.reserve(len)?;
+        {
+            // SAFETY: We are the only one accessing the mutable portion of mmap
+            let buf = unsafe { self.log.mut_slice() };
+            format.encode_batch_into(&mut buf[..len], &batch);
+        }
+
+        // Commit map. If commit failed, leave index untouched
+        self.log.commit(len)?;
+        let uuids = batch.iter().map(|log| log.uuid).collect::<Vec<_>>();
+        self.written(&uuids, offset as _, shared)?;
+        for (done, uuid) in acks.into_iter().zip(uuids) {
+            ack(done, Ok(uuid));
+        }
+
+        Ok(rem)
+    }
+
+    /// Index and count logs with `uuids` just written at `offset`, together
+    /// if they're in a batch.
+    fn written(&mut self, uuids: &[Uuid], offset: u64, shared: &Shared) -> Result<()> {
+        if self.should_index(shared.conf.index_policy, offset) {
+            // SAFETY: We are the only one writing to the index
+            unsafe {
+                self.idx.push(UuidIndex {
+                    uuid: uuids[0],
+                    offset,
+                })?;
+            }
+            self.last_indexed = (self.records, offset);
+        }
+
+        if let Some(dedup) = &mut self.dedup {
+            for &uuid in uuids {
+                dedup.insert(uuid);
+            }
+        }
+
+        if shared.conf.ordering_policy != OrderingPolicy::Allow {
+            *shared.last_uuid.lock().unwrap() = uuids[uuids.len() - 1];
+        }
+
+        if self.records == 0 {
+            self.deadline = shared.conf.roll_interval.map(|i| Instant::now() + i);
+        }
+        self.sync_at
+            .get_or_insert_with(|| Instant::now() + SYNC_INTERVAL);
+        self.records += uuids.len() as u64;
 
         // Write successfully, notify all pending readers
-        event.notify_additional(usize::MAX);
+        shared.event.notify_additional(usize::MAX);
 
-        Ok(None)
+        Ok(())
+    }
+}
+
+/// UUIDs of recently written logs, within a [`DedupWindow`]
+#[derive(Debug)]
+pub struct Dedup {
+    window: DedupWindow,
+    uuids: HashSet<Uuid>,
+    /// UUIDs in the order they are written
+    order: VecDeque<Uuid>,
+}
+
+impl Dedup {
+    pub fn new(window: DedupWindow) -> Self {
+        Self {
+            window,
+            uuids: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Rebuild the window from the newest logs in `dir`.
+    pub fn load(dir: &Path, window: DedupWindow) -> Result<Self> {
+        // Newest first
+        let mut recent = vec![];
+
+        'segments: for name in list_segments(dir)?.iter().rev() {
+            let map = SharedMap::open(dir, name)?;
+            let uuids = match map.index() {
+                Ok(index) if !index.is_sparse() => {
+                    (0..index.len()).rev().map(|i| index.get(i).uuid).collect()
+                }
+                // Not every log is indexed, scan the log instead
+                _ => map.uuids()?.tap_mut(|uuids| uuids.reverse()),
+            };
+
+            for uuid in uuids {
+                if !window.covers(recent.first().copied(), recent.len(), uuid) {
+                    break 'segments;
+                }
+                recent.push(uuid);
+            }
+        }
+
+        let mut this = Self::new(window);
+        for uuid in recent.into_iter().rev() {
+            this.insert(uuid);
+        }
+
+        Ok(this)
+    }
+
+    pub fn contains(&self, uuid: Uuid) -> bool {
+        self.uuids.contains(&uuid)
+    }
+
+    /// Forget UUIDs that don't satisfy `keep`, e.g. those truncated.
+    pub fn retain(&mut self, keep: impl Fn(Uuid) -> bool) {
+        self.order.retain(|&uuid| keep(uuid));
+        self.uuids.retain(|&uuid| keep(uuid));
+    }
+
+    /// Record a written UUID, evicting those out of the window.
+    pub fn insert(&mut self, uuid: Uuid) {
+        if !self.uuids.insert(uuid) {
+            return;
+        }
+        self.order.push_back(uuid);
+
+        let newest = Some(uuid);
+        while let Some(&oldest) = self.order.front() {
+            if self.window.covers(newest, self.order.len() - 1, oldest) {
+                break;
+            }
+            self.order.pop_front();
+            self.uuids.remove(&oldest);
+        }
+    }
+}
+
+impl DedupWindow {
+    /// If `uuid` is in the window, given the newest UUID and number of UUIDs
+    /// newer than it.
+    fn covers(self, newest: Option<Uuid>, newer: usize, uuid: Uuid) -> bool {
+        match (self, newest) {
+            (Self::Count(n), _) => newer < n,
+            (Self::Time(_), None) => true,
+            (Self::Time(d), Some(newest)) => uuid.to_ts() + d.as_millis() as u64 >= newest.to_ts(),
+        }
     }
 }
 
@@ -264,10 +1632,10 @@ fn test_map() {
     use bincode::Options;
     use uuid7::Uuid;
 
-    use crate::Log;
+    use crate::{bincode_option, consts::SmallBytes, Log};
 
     let dir = tempfile::tempdir().unwrap();
-    let map = SharedMap::new(dir.path(), "123", 100).unwrap();
+    let map = SharedMap::new(dir.path(), "123", 100, None, RecordFormat::default()).unwrap();
 
     let (r, w) = unsafe { (map.slice(10), map.mut_slice()) };
 
@@ -277,6 +1645,7 @@ fn test_map() {
     let l = Log {
         uuid: Uuid::MAX,
         body: SmallBytes::from_iter([114u8, 191]),
+        headers: vec![],
     };
 
     bincode_option().serialize_into(&mut w[..], &l).unwrap();
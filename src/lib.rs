@@ -11,41 +11,63 @@
     clippy::must_use_candidate
 )]
 
+pub mod archive;
 pub mod consts;
+pub mod crypto;
 pub mod formats;
+pub mod manifest;
+pub mod repair;
+pub mod replication;
+#[cfg(feature = "server")]
+pub mod server;
+pub mod sink;
+pub mod typed;
 
 mod_use::mod_use![error];
 
 mod inner;
+mod lock;
 mod raw;
+mod truncate;
 mod util;
 
 use std::{
+    future::poll_fn,
     io::{Error as IoError, ErrorKind as IoErrorKind},
+    ops::Deref,
     path::PathBuf,
     pin::Pin,
     sync::Arc,
     task::{Context, Poll},
+    time::Duration,
 };
 
 use event_listener::EventListener;
 use futures_core::{ready, Future, Stream};
-use inner::{Appender, Shared, SharedMap};
-use kanal::SendFuture;
+use inner::{
+    joined, uuid_after, Appender, Dedup, Lookup, Origin, Pending, Queued, Shared, SharedMap,
+};
+use replication::{Follower, Leader};
 use serde::{Deserialize, Serialize};
 use tap::{Conv, Pipe};
-use tokio::{fs, task::JoinHandle};
-use tracing::{instrument, trace};
-use uuid7::uuid7;
+use tokio::{fs, sync::mpsc, task::JoinHandle};
+use tracing::{error, instrument, trace, warn};
+use uuid7::{uuid7, Uuid};
 
 pub use crate::util::{bincode_option, try_decode, BincodeOptions};
 use crate::{
+    archive::{Archive, ArchiveBackend},
     consts::{
-        SmallBytes, DEFAULT_CHANNEL_SIZE, DEFAULT_INDEX_SIZE, DEFAULT_LOG_SIZE, MIN_LOG_SIZE,
+        SmallBytes, DEFAULT_CHANNEL_SIZE, DEFAULT_INDEX_SIZE, DEFAULT_LOG_SIZE,
+        DEFAULT_POLL_INTERVAL, MIN_LOG_SIZE,
     },
-    formats::Log,
-    inner::UniqueMap,
-    util::Discard,
+    crypto::{EncryptionKey, Keyring},
+    formats::{FormatVersion, Headers, Log, RecordFormat},
+    inner::IndexMap,
+    lock::DirLock,
+    manifest::Manifest,
+    sink::WriterSink,
+    truncate::{Truncate, Truncation},
 };
 
 /// Builds [`Topic`] with custom configuration values.
@@ -73,7 +95,22 @@ pub struct TopicBuilder {
     dir: PathBuf,
     log_size: u64,
     index_size: u64,
+    index_policy: IndexPolicy,
+    ordering_policy: OrderingPolicy,
+    dedup_window: Option<DedupWindow>,
+    record_headers: bool,
+    compression: Compression,
+    format_version: FormatVersion,
+    grow_chunk: Option<u64>,
     channel_size: u32,
+    roll_interval: Option<Duration>,
+    max_records: Option<u64>,
+    local_retention: Option<usize>,
+    poll_interval: Duration,
+    #[serde(skip)]
+    archive: Archive,
+    #[serde(skip)]
+    keyring: Keyring,
 }
 
 impl TopicBuilder {
@@ -101,7 +138,20 @@ impl TopicBuilder {
             dir,
             log_size: DEFAULT_LOG_SIZE,
             index_size: DEFAULT_INDEX_SIZE,
+            index_policy: IndexPolicy::Dense,
+            ordering_policy: OrderingPolicy::Allow,
+            dedup_window: None,
+            record_headers: false,
+            compression: Compression::None,
+            format_version: FormatVersion::V1,
+            grow_chunk: None,
             channel_size: DEFAULT_CHANNEL_SIZE,
+            roll_interval: None,
+            max_records: None,
+            local_retention: None,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            archive: Archive::default(),
+            keyring: Keyring::default(),
         })
     }
 
@@ -128,6 +178,62 @@ impl TopicBuilder {
         self
     }
 
+    /// Set how often logs are indexed. See [`IndexPolicy`].
+    pub const fn with_index_policy(mut self, index_policy: IndexPolicy) -> Self {
+        self.index_policy = index_policy;
+        self
+    }
+
+    /// Set how logs with out-of-order UUIDs are handled. See
+    /// [`OrderingPolicy`].
+    pub const fn with_ordering_policy(mut self, ordering_policy: OrderingPolicy) -> Self {
+        self.ordering_policy = ordering_policy;
+        self
+    }
+
+    /// Drop logs whose UUIDs are already written within `window`, so that
+    /// retried writes are only stored once. See [`DedupWindow`].
+    pub const fn with_dedup_window(mut self, window: DedupWindow) -> Self {
+        self.dedup_window = Some(window);
+        self
+    }
+
+    /// Store [`Log::headers`] in new segments. Without it, writing a log with
+    /// headers fails with [`ErrorType::HeadersDisabled`]. Segments written
+    /// either way stay readable after this is changed.
+    pub const fn with_record_headers(mut self, enabled: bool) -> Self {
+        self.record_headers = enabled;
+        self
+    }
+
+    /// Compress bodies of logs in new segments. See [`Compression`].
+    #[cfg(feature = "compression")]
+    pub const fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Write new segments in `version`. With [`FormatVersion::V2`], logs
+    /// queued together are written as a batch, and the index has an entry per
+    /// batch instead of per log. Segments of either version stay readable
+    /// after this is changed.
+    pub const fn with_format_version(mut self, version: FormatVersion) -> Self {
+        self.format_version = version;
+        self
+    }
+
+    /// Grow the log and index files by `chunk` bytes at a time as logs are
+    /// written, instead of allocating the max size upfront. Either way, the
+    /// files are truncated to the written size when finished.
+    ///
+    /// The files are mapped again each time they grow, and readers of the
+    /// topic map them again as they see the committed size move past the
+    /// mapped end.
+    pub const fn with_grow_chunk(mut self, chunk: u64) -> Self {
+        self.grow_chunk = Some(chunk);
+        self
+    }
+
     /// Set channel max size.
     ///
     /// The [`Writer`] will block if the channel is full until write request is
@@ -137,25 +243,92 @@ impl TopicBuilder {
         self
     }
 
+    /// Roll the segment once `interval` has passed since its first log was
+    /// written, even if it's not full. Empty segments are never rolled.
+    pub const fn with_roll_interval(mut self, interval: Duration) -> Self {
+        self.roll_interval = Some(interval);
+        self
+    }
+
+    /// Roll the segment once it contains `max_records` logs, even if it's not
+    /// full.
+    pub const fn with_max_records(mut self, max_records: u64) -> Self {
+        self.max_records = Some(max_records);
+        self
+    }
+
+    /// Archive finished segments to `backend`. See [`archive`] for details.
+    pub fn with_archive(mut self, backend: impl ArchiveBackend) -> Self {
+        self.archive = Archive::new(backend);
+        self
+    }
+
+    /// Keep at most `segments` finished segments in the topic directory.
+    /// Older ones are removed once archived, so this only takes effect with
+    /// [`with_archive`](TopicBuilder::with_archive).
+    pub const fn with_local_retention(mut self, segments: usize) -> Self {
+        self.local_retention = Some(segments);
+        self
+    }
+
+    /// Check for logs written by another process every `interval`, only used
+    /// by read-only topics. See [`Topic::open_read_only`].
+    pub const fn with_poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// Encrypt bodies of logs in new segments with `key`. Keys of existing
+    /// segments must be provided too to read them, with older keys first since
+    /// the last one is used for new segments. See [`crypto`] for details.
+    #[cfg(feature = "encryption")]
+    pub fn with_encryption_key(mut self, key: EncryptionKey) -> Self {
+        self.keyring.insert(key);
+        self
+    }
+
+    /// Returns the topic name.
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
     /// Returns the topic directory where the `.limlog` and `.idx` files placed.
     pub fn topic_dir(&self) -> PathBuf {
         self.dir.join(&self.topic)
     }
 
+    /// Returns the format of records in new segments, without encryption.
+    pub const fn record_format(&self) -> RecordFormat {
+        RecordFormat {
+            version: self.format_version,
+            headers: self.record_headers,
+            compression: self.compression,
+            key_id: None,
+        }
+    }
+
     /// Construct a [`Topic`] instant if configurations is valid.
     pub async fn build(self) -> Result<Topic> {
         Topic::new(self).await
     }
+
+    /// Construct a read-only [`Topic`], see [`Topic::open_read_only`].
+    pub fn build_read_only(self) -> Result<Topic> {
+        Topic::new_read_only(self)
+    }
 }
 
 /// The topic which is used to read and write logs. Background task will keep
-/// running even if this struct is dropped. To stop the task, call
-/// [`stop`](Topic::stop) or [`abort`](Topic::abort).
+/// running even if this struct is dropped, until all [`Writer`]s are dropped
+/// too. To stop the task, call [`stop`](Topic::stop) or
+/// [`abort`](Topic::abort).
 #[derive(Debug)]
 pub struct Topic {
     shared: Arc<Shared>,
     handle: JoinHandle<Result<()>>,
-    send: kanal::AsyncSender<Log>,
+    send: kanal::AsyncSender<Queued>,
+    /// Fencing epoch of the writer, `None` if read-only
+    epoch: Option<u64>,
 }
 
 impl Topic {
@@ -169,73 +342,294 @@ impl Topic {
 
     /// Create a new [`Topic`] with [`TopicBuilder`].
     ///
-    /// Equivalent to [`TopicBuilder::build`].
+    /// Equivalent to [`TopicBuilder::build`]. Fails with
+    /// [`ErrorType::Locked`] if another writer has the topic open, in this
+    /// process or another. The topic directory is locked until the background
+    /// task is finished.
+    ///
+    /// The configuration is checked against the one the topic was created
+    /// with, see [`manifest`].
     pub async fn new(conf: TopicBuilder) -> Result<Self> {
         let (send, recv) = kanal::bounded_async(conf.channel_size as _);
+        let (send_truncation, truncations) = mpsc::unbounded_channel();
 
         let dir = conf.topic_dir();
         fs::create_dir_all(&dir).await?;
+        let lock = DirLock::acquire(&dir)?;
+        let epoch = lock.epoch();
+        Manifest::update(&conf)?;
+        // Replaying truncations and repairing segments scan whole segments
+        let (conf, start) = joined(
+            tokio::task::spawn_blocking(move || {
+                truncate::replay(&dir, conf.archive.backend())?;
+                repair::recover(&dir)?;
+                truncate::start(&dir, conf.archive.backend()).map(|start| (conf, start))
+            })
+            .await,
+        )?;
+        let dir = conf.topic_dir();
+
+        let last_uuid = match conf.ordering_policy {
+            OrderingPolicy::Allow => None,
+            _ => inner::last_uuid(&dir)?,
+        };
+
+        let dedup = conf
+            .dedup_window
+            .map(|window| Dedup::load(&dir, window))
+            .transpose()?;
+
+        let key = conf.keyring.active().cloned();
+        let last = inner::list_segments(&dir)?.pop();
+        let (log_map, appender) =
+            Self::make(&conf, last.as_deref(), recv, truncations, dedup, key)?;
+        let shared = Arc::new(Shared::new(
+            conf,
+            log_map,
+            last_uuid.unwrap_or(Uuid::NIL),
+            start,
+            send_truncation,
+        ));
+        let handle = tokio::spawn(Self::background(shared.clone(), appender, lock));
+
+        Ok(Self {
+            shared,
+            handle,
+            send,
+            epoch: Some(epoch),
+        })
+    }
+
+    /// Open the topic in `dir`, i.e. [`TopicBuilder::topic_dir`], read-only
+    /// while another process writes it, e.g. to tail it from a sidecar.
+    ///
+    /// Segments are mapped read-only without locks, and nothing in the
+    /// directory is changed, so segments left unclean by a crashed writer are
+    /// not repaired. The committed length of the segment being written is
+    /// loaded from the watermark in its header, and the directory is polled
+    /// for new segments and truncations every
+    /// [`TopicBuilder::with_poll_interval`]. Readers are woken up by the
+    /// polling instead of by writes. Writes and truncations fail with
+    /// [`ErrorType::ReadOnly`].
+    ///
+    /// The segment being written is mapped with the sizes in the
+    /// [`manifest`], or those of the builder if the writer didn't store one.
+    /// Use [`TopicBuilder::build_read_only`] to set keys to read an encrypted
+    /// topic. Must be called within a Tokio runtime.
+    pub fn open_read_only(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        let (Some(parent), Some(topic)) = (dir.parent(), dir.file_name()) else {
+            return IoError::new(IoErrorKind::InvalidInput, dir.display().to_string())
+                .conv::<ErrorType>()
+                .pipe(Err);
+        };
+
+        TopicBuilder::new_with_dir(topic.to_string_lossy(), parent)?.build_read_only()
+    }
+
+    /// Create a read-only [`Topic`] with [`TopicBuilder`].
+    ///
+    /// Equivalent to [`TopicBuilder::build_read_only`].
+    pub fn new_read_only(mut conf: TopicBuilder) -> Result<Self> {
+        let dir = conf.topic_dir();
+        let start = truncate::load_start(&dir)?;
+
+        if let Some(manifest) = Manifest::load(&dir)? {
+            conf.log_size = manifest.config.log_size;
+            conf.index_size = manifest.config.index_size;
+        }
+
+        let Some(name) = inner::list_segments(&dir)?.pop() else {
+            return IoError::new(IoErrorKind::NotFound, format!("No segment in {}", dir.display()))
+                .conv::<ErrorType>()
+                .pipe(Err);
+        };
+        let map = SharedMap::open_live(&dir, &name, conf.log_size, conf.index_size)?;
+
+        let shared = Arc::new(Shared::read_only(conf, Arc::new(map), start));
+        let handle = tokio::spawn(Self::watch(shared.clone()));
 
-        let (log_map, appender) = Self::make(&conf, recv)?;
-        let shared = Arc::new(Shared::new(conf, log_map));
-        let handle = tokio::spawn(Self::background(shared.clone(), appender));
+        // Writes fail before they're sent
+        let (send, _) = kanal::bounded_async(0);
 
         Ok(Self {
             shared,
             handle,
             send,
+            epoch: None,
         })
     }
 
+    /// Create a new segment, named after segment `last` if there's one.
     fn make(
         conf: &TopicBuilder,
-        recv: kanal::AsyncReceiver<Log>,
+        last: Option<&str>,
+        recv: kanal::AsyncReceiver<Queued>,
+        truncations: mpsc::UnboundedReceiver<Truncation>,
+        dedup: Option<Dedup>,
+        key: Option<EncryptionKey>,
     ) -> Result<(Arc<SharedMap>, Appender)> {
-        let filename = uuid7().encode();
+        // Segments are found by sorting their names, which must not go backwards even
+        // if UUIDs are generated on another thread or the clock is behind
+        let filename = last
+            .and_then(|name| name.parse().ok())
+            .map_or_else(uuid7, uuid_after)
+            .encode();
 
         let dir = conf.topic_dir();
 
         trace!(?dir, id = %filename, "Rolling");
 
-        let log_map = SharedMap::new(&dir, filename.as_str(), conf.log_size)?.pipe(Arc::new);
-        let idx_map = UniqueMap::new(&dir, filename.as_str(), conf.index_size)?;
+        let log_map = SharedMap::new(
+            &dir,
+            filename.as_str(),
+            conf.log_size,
+            conf.grow_chunk,
+            RecordFormat {
+                key_id: key.as_ref().map(EncryptionKey::id),
+                ..conf.record_format()
+            },
+        )?
+        .pipe(Arc::new);
+        let idx_map = IndexMap::new(&dir, filename.as_str(), conf.index_size, conf.grow_chunk)?
+            .pipe(Arc::new);
+        log_map.set_index(idx_map.clone());
+        // Batches are indexed by their first logs
+        if conf.index_policy != IndexPolicy::Dense || conf.format_version == FormatVersion::V2 {
+            idx_map.mark_sparse();
+        }
+
         let appender = Appender {
             log: log_map.clone(),
             idx: idx_map,
             recv,
+            truncations,
+            truncation: None,
+            records: 0,
+            last_indexed: (0, 0),
+            deadline: None,
+            sync_at: None,
+            dedup,
+            key,
         };
 
         Ok((log_map, appender))
     }
 
     #[instrument(level = "trace")]
-    async fn background(shared: Arc<Shared>, mut appender: Appender) -> Result<()> {
-        // Remaining log that wasn't saved due to lack of file space. Will be written to
-        // the next file.
-        let mut rem = None;
-        loop {
-            // Start receiving and save logs
-            rem = appender.run(rem, &shared).await?;
+    async fn background(shared: Arc<Shared>, appender: Appender, lock: DirLock) -> Result<()> {
+        // Keep the channel open until the failure is recorded, so writers finding
+        // the channel closed are always able to see the cause.
+        let recv = appender.recv.clone();
+
+        let res = match Self::run(&shared, appender).await {
+            res @ (Ok(()) | Err(ErrorType::Shutdown)) => {
+                // Nothing more will be written, so readers end once they've read the active
+                // segment
+                if let Err(e) = shared.map().finish() {
+                    warn!(error = %e, "Failed to flush the active segment");
+                }
+                shared.event.notify_additional(usize::MAX);
+                res
+            }
+            Err(e) => {
+                error!(error = %e, "Background task failed");
+                Err(ErrorType::Background(shared.fail(e)))
+            }
+        };
+
+        drop(recv);
+        drop(lock);
+        res
+    }
 
-            let Appender { log, recv, idx } = appender;
+    /// Background task of a read-only topic, which refreshes it until stopped.
+    #[instrument(level = "trace")]
+    async fn watch(shared: Arc<Shared>) -> Result<()> {
+        match shared.watch().await {
+            res @ (Ok(()) | Err(ErrorType::Shutdown)) => res,
+            Err(e) => {
+                error!(error = %e, "Failed to refresh read-only topic");
+                Err(ErrorType::Background(shared.fail(e)))
+            }
+        }
+    }
 
-            // Close the log file and flush to disk
-            idx.drop();
+    async fn run(shared: &Arc<Shared>, mut appender: Appender) -> Result<()> {
+        // Remaining logs that weren't saved due to lack of file space. Will be written
+        // to the next file.
+        let mut rem = vec![];
+        loop {
+            // Start receiving and save logs
+            rem = appender.run(rem, shared).await?;
 
-            log.finish()?;
+            let Appender {
+                log,
+                recv,
+                truncations,
+                truncation,
+                dedup,
+                ..
+            } = appender;
 
             // Log file is full, create a new one
-            let (map, app) = Self::make(&shared.conf, recv)?;
+            let (map, app) = Self::make(
+                &shared.conf,
+                Some(log.name()),
+                recv,
+                truncations,
+                dedup,
+                shared.active_key(),
+            )?;
 
             appender = app;
             shared.swap_map(map);
+
+            // Only mark the old map as finished and flush it to disk after the new one
+            // is in place, so readers that find it finished always have a map to move
+            // on to.
+            log.finish()?;
+            drop(log);
+
+            // Truncate once logs queued before are all written to finished segments
+            match truncation {
+                Some(Truncation { truncate, done }) if rem.is_empty() => {
+                    // Segments are scanned and removed, and archiving may hold the lock
+                    let (shared, mut dedup) = (shared.clone(), appender.dedup.take());
+                    let (applied, dedup) = joined(
+                        tokio::task::spawn_blocking(move || {
+                            let applied = shared.apply_truncation(truncate, dedup.as_mut());
+                            Ok((applied, dedup))
+                        })
+                        .await,
+                    )?;
+                    appender.dedup = dedup;
+
+                    if let Err(e) = &applied {
+                        error!(error = %e, "Failed to truncate");
+                    }
+                    done.send(applied).ok();
+                }
+                truncation => appender.truncation = truncation,
+            }
+
+            if shared.conf.archive.backend().is_some() {
+                let shared = shared.clone();
+                tokio::task::spawn_blocking(move || {
+                    if let Err(e) = shared.archive() {
+                        error!(error = %e, "Failed to archive segments");
+                    }
+                });
+            }
         }
     }
 
-    /// Write a [`Log`] asynchronous.
-    pub async fn write_one(&self, log: Log) -> Result<()> {
-        self.send.send(log).await?;
-        Ok(())
+    /// Write a [`Log`] asynchronous and return the UUID it's written with,
+    /// which is its own unless it's out of order and the [`OrderingPolicy`]
+    /// is [`Restamp`](OrderingPolicy::Restamp). Fails with
+    /// [`ErrorType::OutOfOrder`] if it's [`Reject`](OrderingPolicy::Reject).
+    pub async fn write_one(&self, log: Log) -> Result<Uuid> {
+        self.shared.send(&self.send, log, Origin::Given).await
     }
 
     /// Returns the [`Writer`] to write logs.
@@ -251,9 +645,20 @@ impl Topic {
     pub fn writer(&self) -> Writer {
         Writer {
             send: self.send.clone(),
+            shared: self.shared.clone(),
         }
     }
 
+    /// Returns a [`Leader`] to replicate this topic to followers.
+    pub fn leader(&self) -> Leader {
+        Leader::new(self.shared.clone())
+    }
+
+    /// Returns a [`Follower`] to replicate a leader topic into this topic.
+    pub fn follower(&self) -> Follower {
+        Follower::new(self.writer())
+    }
+
     /// Returns the [`Reader`] to read logs.
     ///
     /// ```ignore
@@ -268,8 +673,13 @@ impl Topic {
 
         Reader {
             read_at: map.offset(),
+            skip: 0,
+            pending: Pending::new(),
+            batch: (0, 0),
+            error: None,
             notify: shared.subscribe(),
             map,
+            lookup: None,
             shared,
         }
     }
@@ -289,12 +699,160 @@ impl Topic {
 
         Ok(Reader {
             read_at,
+            skip: 0,
+            pending: Pending::new(),
+            batch: (0, 0),
+            error: None,
+            notify: shared.subscribe(),
+            map,
+            lookup: None,
+            shared,
+        })
+    }
+
+    /// Create a [`Reader`] starting from the first log whose UUID is not less
+    /// than `uuid`, in any segment of the topic.
+    ///
+    /// Archived segments are downloaded back if needed, which blocks.
+    pub fn reader_from(&self, uuid: Uuid) -> Result<Reader> {
+        let shared = self.shared.clone();
+        let (map, read_at, skip) = shared.seek(uuid)?;
+
+        Ok(Reader {
+            read_at,
+            skip,
+            pending: Pending::new(),
+            batch: (0, 0),
+            error: None,
+            notify: shared.subscribe(),
+            map,
+            lookup: None,
+            shared,
+        })
+    }
+
+    /// Create a [`Reader`] resuming from `position`, returned by
+    /// [`Reader::position`]. Fails with [`ErrorType::Truncated`] if the logs
+    /// there were truncated since.
+    ///
+    /// Archived segments are downloaded back if needed, which blocks.
+    pub fn reader_at_position(&self, position: &Position) -> Result<Reader> {
+        let shared = self.shared.clone();
+        let map = shared
+            .segment(&position.segment)?
+            .ok_or(ErrorType::Truncated)?;
+        let read_at = position.offset as usize;
+        map.check(read_at)?;
+
+        Ok(Reader {
+            read_at,
+            skip: position.skip as _,
+            pending: Pending::new(),
+            batch: (0, 0),
+            error: None,
             notify: shared.subscribe(),
             map,
+            lookup: None,
             shared,
         })
     }
 
+    /// Returns a finite [`Stream`] of logs whose UUIDs are in `start..=end`,
+    /// in any segment of the topic. The stream ends at the first log past
+    /// `end`, or when all written logs are consumed, without waiting for new
+    /// ones.
+    ///
+    /// Archived segments are downloaded back if needed, which blocks.
+    ///
+    /// ```ignore
+    /// use futures::TryStreamExt;
+    ///
+    /// let logs: Vec<Log> = topic.range(start, end)?.try_collect().await?;
+    /// ```
+    pub fn range(&self, start: Uuid, end: Uuid) -> Result<Range> {
+        let shared = self.shared.clone();
+        let (map, read_at, skip) = shared.seek(start)?;
+
+        Ok(Range {
+            map: Some(map),
+            read_at,
+            skip,
+            pending: Pending::new(),
+            lookup: None,
+            end,
+            shared,
+        })
+    }
+
+    /// Returns the log with `uuid`, in any segment of the topic.
+    ///
+    /// The segment is found by its name and the log by the index of the
+    /// segment, so logs written out of order with [`OrderingPolicy::Allow`]
+    /// may not be found. Archived segments are downloaded back if needed,
+    /// which blocks.
+    pub fn get(&self, uuid: Uuid) -> Result<Option<Log>> {
+        let (map, read_at, skip) = self.shared.seek(uuid)?;
+        let Some((mut records, _)) = map.decode(read_at)? else { return Ok(None) };
+
+        match records.remove(skip) {
+            Some((at, log)) if log.uuid == uuid => self
+                .shared
+                .decode_body(map.name(), at, map.format(), log)
+                .map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Same as [`get`](Topic::get), for each of `uuids`.
+    pub fn get_many(&self, uuids: &[Uuid]) -> Result<Vec<Option<Log>>> {
+        uuids.iter().map(|&uuid| self.get(uuid)).collect()
+    }
+
+    /// Drop logs whose UUIDs are greater than `uuid`, e.g. a conflicting tail
+    /// of a Raft log. Logs written before are dropped too if they're after
+    /// `uuid`, and the segment being written is rolled, so logs written after
+    /// this returns follow the kept ones.
+    ///
+    /// The truncation is persisted before it returns, and finished when the
+    /// topic is opened next if it's interrupted by a crash. Readers positioned
+    /// after `uuid` fail with [`ErrorType::Truncated`] instead of reading the
+    /// dropped logs. Like lookups, this assumes UUIDs increase, see
+    /// [`OrderingPolicy`].
+    ///
+    /// Archived segments are changed too, so this fails if the
+    /// [`ArchiveBackend`] can't remove them, see
+    /// [`ArchiveBackend::can_remove`].
+    pub async fn truncate_after(&self, uuid: Uuid) -> Result<()> {
+        self.shared.truncate(Truncate::After(uuid)).await
+    }
+
+    /// Drop logs whose UUIDs are less than `uuid`, e.g. a prefix covered by a
+    /// snapshot. Segments that only have such logs are removed, and the rest
+    /// of them are hidden until the segment containing `uuid` is removed.
+    ///
+    /// Same as [`truncate_after`](Topic::truncate_after), the truncation is
+    /// ordered with logs written before, persisted before it returns, and
+    /// readers positioned before `uuid` fail with [`ErrorType::Truncated`].
+    pub async fn truncate_before(&self, uuid: Uuid) -> Result<()> {
+        self.shared.truncate(Truncate::Before(uuid)).await
+    }
+
+    /// Encrypt new segments with `key` from now on. The segment being written
+    /// is rolled before the next log is written. `key` is kept to read the
+    /// segments it encrypts, but it must be provided with
+    /// [`TopicBuilder::with_encryption_key`] when the topic is opened again.
+    #[cfg(feature = "encryption")]
+    pub fn rotate_key(&self, key: &EncryptionKey) {
+        self.shared.rotate_key(key);
+    }
+
+    /// Returns the fencing epoch of this writer, which is greater than that of
+    /// every writer that opened the topic before, e.g. to reject requests from
+    /// a stale leader. `None` if the topic is read-only.
+    pub const fn epoch(&self) -> Option<u64> {
+        self.epoch
+    }
+
     /// Returns the topic configurations.
     pub fn config(&self) -> &TopicBuilder {
         &self.shared.conf
@@ -304,7 +862,8 @@ impl Topic {
     /// but the task can take time to finish. Use with [`join`](Topic::join) to
     /// gracefully shutdown.
     pub fn stop(&self) {
-        self.shared.stop.notify_waiters();
+        // Store a permit so the signal is not lost if the task is busy writing
+        self.shared.stop.notify_one();
     }
 
     /// Check if the background task is finished.
@@ -312,13 +871,24 @@ impl Topic {
         self.handle.is_finished()
     }
 
+    /// Returns the state of the background task.
+    pub fn health(&self) -> Health {
+        match self.shared.failure() {
+            Some(e) => Health::Failed(e),
+            None if self.handle.is_finished() => Health::Stopped,
+            None => Health::Running,
+        }
+    }
+
     /// Abort background task.
     pub fn abort(&self) {
         self.handle.abort();
     }
 
     /// Wait for background task to complete. This can be used with
-    /// [`stop`](Topic::stop) to gracefully shutdown.
+    /// [`stop`](Topic::stop) to gracefully shutdown, in which case
+    /// [`ErrorType::Shutdown`] is returned. If the task failed, the error is
+    /// returned as [`ErrorType::Background`].
     ///
     /// # Panics
     /// Panics if the background task panicked.
@@ -328,32 +898,240 @@ impl Topic {
     }
 }
 
+/// How often logs are indexed in `.idx` files, set with
+/// [`TopicBuilder::with_index_policy`].
+///
+/// The first log of each segment is always indexed. Lookups by UUID
+/// binary-search the index, then scan forward in the log, so a sparse index
+/// trades lookup speed for smaller `.idx` files and fewer rolls caused by full
+/// index files.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum IndexPolicy {
+    /// Index every log.
+    #[default]
+    Dense,
+    /// Index a log every `n` logs.
+    Records(u64),
+    /// Index a log once at least `n` bytes of logs are written after the last
+    /// indexed one.
+    Bytes(u64),
+}
+
+/// How logs whose UUIDs are not greater than the last written one are
+/// handled, set with [`TopicBuilder::with_ordering_policy`].
+///
+/// Lookups by UUID assume UUIDs increase within a topic. UUIDs generated by
+/// [`Writer::write`] are only in order within a thread, and those written
+/// with [`Writer::write_one`] may be anything. The order is checked by the
+/// background task as logs are written. Unless the policy is
+/// [`Allow`](OrderingPolicy::Allow), writes wait until then, so they return
+/// the UUID logs are written with or why they're rejected.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum OrderingPolicy {
+    /// Write logs as is.
+    #[default]
+    Allow,
+    /// Fail the write with [`ErrorType::OutOfOrder`]. Logs written with
+    /// [`Writer::write`] get a new UUID instead, since theirs are generated.
+    Reject,
+    /// Replace the UUID with a new one.
+    Restamp,
+}
+
+/// Range of recently written logs checked for duplicated UUIDs, set with
+/// [`TopicBuilder::with_dedup_window`].
+///
+/// Duplicates are dropped by the background task, and retries are
+/// acknowledged as if they were written. The window is rebuilt
+/// from the newest segments when the topic is opened.
+///
+/// Logs are checked before their order, so retries are dropped even with
+/// [`OrderingPolicy::Restamp`].
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum DedupWindow {
+    /// The last `n` logs.
+    Count(usize),
+    /// Logs with UUID timestamps within the duration before the last one.
+    Time(Duration),
+}
+
+/// How bodies of logs are compressed in new segments, set with
+/// [`TopicBuilder::with_compression`].
+///
+/// Each body is compressed on its own before it's encrypted, if encryption is
+/// enabled. The codec is recorded in the header of each segment, so segments
+/// written with different codecs stay readable. Compressed segments can only
+/// be read with feature `compression`.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum Compression {
+    /// Store bodies as is.
+    #[default]
+    None,
+    /// Compress with [Snappy](https://github.com/google/snappy), fast with a
+    /// moderate ratio.
+    Snappy,
+}
+
+impl Compression {
+    #[cfg(not(feature = "compression"))]
+    const DISABLED: &'static str = "Snappy compression requires feature `compression`";
+
+    /// Returns `log` with its body compressed, or `None` if there's no
+    /// compression.
+    #[cfg(feature = "compression")]
+    pub(crate) fn compress(self, log: &Log) -> Result<Option<Log>> {
+        let body = match self {
+            Self::None => return Ok(None),
+            Self::Snappy => snap::raw::Encoder::new()
+                .compress_vec(&log.body)
+                .map_err(|e| ErrorType::Encode(e.into()))?,
+        };
+
+        Ok(Some(Log {
+            uuid: log.uuid,
+            body: SmallBytes::from_vec(body),
+            headers: log.headers.clone(),
+        }))
+    }
+
+    #[cfg(feature = "compression")]
+    pub(crate) fn decompress(self, mut log: Log) -> Result<Log> {
+        log.body = match self {
+            Self::None => return Ok(log),
+            Self::Snappy => snap::raw::Decoder::new()
+                .decompress_vec(&log.body)
+                .map_err(|e| ErrorType::Decode(e.into()))?
+                .pipe(SmallBytes::from_vec),
+        };
+
+        Ok(log)
+    }
+
+    // Compression can't be enabled without the feature, but segments written
+    // with it can't be read either
+    #[cfg(not(feature = "compression"))]
+    #[allow(clippy::unnecessary_wraps)]
+    pub(crate) fn compress(self, _log: &Log) -> Result<Option<Log>> {
+        match self {
+            Self::None => Ok(None),
+            Self::Snappy => Err(ErrorType::Encode(Self::DISABLED.into())),
+        }
+    }
+
+    #[cfg(not(feature = "compression"))]
+    #[allow(clippy::needless_pass_by_value)]
+    pub(crate) fn decompress(self, log: Log) -> Result<Log> {
+        match self {
+            Self::None => Ok(log),
+            Self::Snappy => Err(ErrorType::Decode(Self::DISABLED.into())),
+        }
+    }
+}
+
+/// State of the background task of a [`Topic`], returned by
+/// [`Topic::health`].
+#[derive(Debug, Clone)]
+pub enum Health {
+    /// The background task is running and accepting logs.
+    Running,
+    /// The background task has been stopped or aborted.
+    Stopped,
+    /// The background task failed. Writers and readers will get this error
+    /// wrapped in [`ErrorType::Background`].
+    Failed(Arc<ErrorType>),
+}
+
 /// A writer to write logs to a topic.
 #[derive(Clone, Debug)]
 pub struct Writer {
-    send: kanal::AsyncSender<Log>,
+    send: kanal::AsyncSender<Queued>,
+    shared: Arc<Shared>,
 }
 
 impl Writer {
     /// Write log with `body` and generated UUID.
-    pub fn write(&self, body: impl Into<SmallBytes>) -> SendFuture<'_, Log> {
-        self.send.send(Log {
-            uuid: uuid7(),
-            body: body.into(),
-        })
+    ///
+    /// If the background task failed, the error causing it is returned.
+    pub fn write(&self, body: impl Into<SmallBytes>) -> impl Future<Output = Result<()>> + '_ {
+        let log = Log::new(body);
+        async move {
+            self.send(log, Origin::Generated).await?;
+            Ok(())
+        }
+    }
+
+    /// Write log with `body`, `headers` and generated UUID. Headers must be
+    /// enabled with [`TopicBuilder::with_record_headers`].
+    pub fn write_with_headers(
+        &self,
+        body: impl Into<SmallBytes>,
+        headers: Headers,
+    ) -> impl Future<Output = Result<()>> + '_ {
+        let log = Log {
+            headers,
+            ..Log::new(body)
+        };
+        async move {
+            self.send(log, Origin::Generated).await?;
+            Ok(())
+        }
+    }
+
+    /// Write a [`Log`], see [`Topic::write_one`].
+    pub async fn write_one(&self, log: Log) -> Result<Uuid> {
+        self.send(log, Origin::Given).await
+    }
+
+    /// Write `log` and return the UUID it's written with.
+    pub(crate) async fn send(&self, log: Log, origin: Origin) -> Result<Uuid> {
+        self.shared.send(&self.send, log, origin).await
+    }
+
+    /// Returns a [`Sink`](futures_sink::Sink) of [`Log`]s and bodies writing
+    /// with this writer. See [`WriterSink`].
+    pub fn sink(&self) -> WriterSink {
+        WriterSink::new(self.clone())
     }
 }
 
+/// Position of a log in a topic, returned by [`Reader::position`]. Unlike
+/// offsets of the active segment, it stays valid as segments roll.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Position {
+    /// Name of the segment
+    pub segment: String,
+    /// Offset of the record in the segment
+    pub offset: u64,
+    /// Number of logs before it in the record, if it's a batch
+    pub skip: u32,
+}
+
 pin_project_lite::pin_project! {
     /// A reader to read logs from a topic with [`Stream`] interface.
+    ///
+    /// If the background task failed, the reader yields the error wrapped in
+    /// [`ErrorType::Background`] once all written logs are consumed. If it was
+    /// stopped, the stream ends there instead.
     #[derive(Debug)]
     pub struct Reader {
         #[pin]
         notify: EventListener,
         // Current read position
         read_at: usize,
+        // Number of logs to skip in the next decoded records, set when seeking into
+        // the middle of a batch
+        skip: usize,
+        // Logs of a decoded batch that are not yielded yet, they're before `read_at`
+        pending: Pending,
+        // Offset of the records `pending` is decoded from, and the number of their logs
+        // before it
+        batch: (usize, usize),
+        // Error found after some logs were read by `next_batch`, yielded on the next read
+        error: Option<ErrorType>,
         // Map being reading, may not be the latest one
         map: Arc<SharedMap>,
+        // Lookup of the segment after a finished `map`, which may block
+        lookup: Option<Lookup>,
         // Up to date shared info
         shared: Arc<Shared>
     }
@@ -362,54 +1140,165 @@ pin_project_lite::pin_project! {
 impl Reader {
     // Get the unread bytes. This will start at the log boundary. (i.e. followed
     // by a valid log or nothing)
-    pub fn as_slice(&self) -> &[u8] {
+    pub fn as_slice(&self) -> impl Deref<Target = [u8]> + '_ {
         self.map.slice(self.read_at)
     }
 
-    /// Returns the current cursor.
+    /// Returns the current cursor, the offset in the current segment up to
+    /// which logs are decoded. Logs of a batch that are decoded but not
+    /// yielded yet are before it, so it's not a position to resume reading
+    /// from: use [`position`](Reader::position) instead, or pass
+    /// [`Topic::reader_from`] a UUID right after the last log read.
     pub const fn cursor(&self) -> usize {
         self.read_at
     }
-}
 
-impl Clone for Reader {
-    /// Clone the reader which will have the same read position and map. For
-    /// fresh map, use [`Topic::reader`] or [`Topic::reader_at`] instead.
-    fn clone(&self) -> Self {
-        Self {
-            notify: self.shared.subscribe(),
-            read_at: self.read_at,
-            map: self.map.clone(),
-            shared: self.shared.clone(),
+    /// Returns the position of the next log to read, which another reader can
+    /// resume from with [`Topic::reader_at_position`].
+    pub fn position(&self) -> Position {
+        let (offset, skip) = if self.pending.is_empty() {
+            (self.read_at, self.skip)
+        } else {
+            self.batch
+        };
+
+        Position {
+            segment: self.map.name().to_owned(),
+            offset: offset as _,
+            skip: skip as _,
         }
     }
-}
 
-impl Stream for Reader {
-    type Item = Result<Log>;
+    /// Read all available logs, up to `max_records` logs and `max_bytes` in
+    /// total [`Log::byte_len`] of logs before they're decrypted and
+    /// decompressed, which is not the space they take in segments. At least one
+    /// log is read regardless of its size.
+    ///
+    /// If no log is available, wait up to `timeout` for one, and return an
+    /// empty batch if there's still none. If the background task failed, the
+    /// error is returned once all written logs are consumed, same as
+    /// [`Stream::poll_next`].
+    ///
+    /// ```ignore
+    /// loop {
+    ///     let logs = r.next_batch(100, 1 << 20, Duration::from_secs(1)).await?;
+    ///     process(logs);
+    /// }
+    /// ```
+    pub async fn next_batch(
+        &mut self,
+        max_records: usize,
+        max_bytes: usize,
+        timeout: Duration,
+    ) -> Result<Vec<Log>> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        if max_records == 0 {
+            return Ok(vec![]);
+        }
 
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        let first = poll_fn(|cx| Pin::new(&mut *self).poll_log(cx, usize::MAX));
+        let first = match tokio::time::timeout_at(deadline, first).await {
+            Ok(first) => first?,
+            Err(_) => None,
+        };
+        let Some((log, mut bytes)) = first else { return Ok(vec![]) };
+
+        // Only take what's immediately available after the first one, which includes
+        // logs in the next segment once it's looked up
+        let mut logs = vec![log];
+        while logs.len() < max_records {
+            let max = max_bytes.saturating_sub(bytes);
+            let next = poll_fn(|cx| match Pin::new(&mut *self).poll_log(cx, max) {
+                Poll::Pending if self.lookup.is_some() => Poll::Pending,
+                polled => Poll::Ready(polled),
+            });
+            match next.await {
+                Poll::Ready(Ok(Some((log, len)))) => {
+                    logs.push(log);
+                    bytes += len;
+                }
+                Poll::Ready(Ok(None)) | Poll::Pending => break,
+                // Don't lose logs already read
+                Poll::Ready(Err(e)) => {
+                    self.error = Some(e);
+                    break;
+                }
+            }
+        }
+
+        Ok(logs)
+    }
+
+    /// Poll the next log with its [`Log::byte_len`], moving on to the next
+    /// segment once the current one is finished. Returns `None` once there's
+    /// no more log, or the failure of the background task if that's why.
+    ///
+    /// A log longer than `max_bytes` is kept for the next poll, returning
+    /// `Poll::Pending` without waiting for anything.
+    fn poll_log(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        max_bytes: usize,
+    ) -> Poll<Result<Option<(Log, usize)>>> {
         let this = self.project();
         let (map, mut notify) = (this.map, this.notify);
 
         loop {
+            if let Some((_, log)) = this.pending.front() {
+                let len = log.byte_len();
+                if len > max_bytes {
+                    return Poll::Pending;
+                }
+
+                let (at, log) = this.pending.pop_front().unwrap();
+                this.batch.1 += 1;
+                return this
+                    .shared
+                    .check_truncated(map, at, log.uuid)
+                    .and_then(|_| this.shared.decode_body(map.name(), at, map.format(), log))
+                    .map(|log| Some((log, len)))
+                    .pipe(Poll::Ready);
+            }
+
+            map.check(*this.read_at)?;
+
+            // Load this before the offset, the last logs may be committed in between
+            let finished = map.is_finished();
+
             // We don't have enough data to decode a log. Check if the map is closed and if
             // any event has been emitted.
             if map.offset() - *this.read_at < MIN_LOG_SIZE {
-                // Current map is obsolete
-                if map.is_finished() {
-                    let current = this.shared.map();
-
-                    // If the active map is finished, the background task is not making more
-                    // progress (swap the map). Mark the reader as finished.
-                    if current.is_finished() {
-                        return Poll::Ready(None);
-                    }
+                // Current map is obsolete, move on to the next segment and reset the read
+                // pointer.
+                if finished {
+                    let lookup = this
+                        .lookup
+                        .get_or_insert_with(|| this.shared.spawn_next_segment(map.name()));
+                    let next = joined(ready!(Pin::new(lookup).poll(cx)));
+                    *this.lookup = None;
 
-                    // Otherwise, swap it with the active one and reset the read
-                    // pointer.
-                    *map = current;
+                    // No segment after a finished one, the background task is not making
+                    // more progress. Mark the reader as finished, or report the failure if
+                    // that's why it stopped.
+                    let Some(next) = next? else {
+                        return this
+                            .shared
+                            .failure()
+                            .map_or(Ok(None), |e| Err(ErrorType::Background(e)))
+                            .pipe(Poll::Ready);
+                    };
+
+                    *map = next;
                     *this.read_at = 0;
+                    continue;
+                }
+
+                // No more data will come if the background task failed.
+                if let Some(e) = this.shared.failure() {
+                    return Poll::Ready(Err(ErrorType::Background(e)));
                 }
 
                 // Poll the event listener. If no event has been emitted, return
@@ -419,28 +1308,139 @@ impl Stream for Reader {
                 std::mem::replace(&mut *notify, this.shared.subscribe()).discard();
             }
 
-            let slice = map.slice(*this.read_at);
-
-            match try_decode::<Log>(slice) {
-                // Successfully decoded a log. Advance the read pointer.
-                Ok(Some((log, read))) => {
-                    *this.read_at += read as usize;
-                    return Poll::Ready(Some(Ok(log)));
+            match map.decode(*this.read_at)? {
+                // Successfully decoded logs. Advance the read pointer and yield them.
+                Some((mut records, next)) => {
+                    let skip = std::mem::take(this.skip).min(records.len());
+                    records.drain(..skip);
+                    *this.batch = (*this.read_at, skip);
+                    *this.pending = records;
+                    *this.read_at = next;
                 }
 
-                // Error while decoding.
-                Err(e) => {
-                    return Poll::Ready(Some(Err(ErrorType::Bincode(e))));
+                // The segment ends with a torn write, which should have been repaired when the
+                // topic was opened (see `repair`). Skip the rest of it, no more data will come.
+                None if map.is_finished() => {
+                    warn!(
+                        name = map.name(),
+                        offset = *this.read_at,
+                        "Skipping torn write at the end of segment"
+                    );
+                    *this.read_at = map.offset();
                 }
 
                 // This should not happen. If it does, there's some problem with the writer, we need
                 // to wait for the next chunk of data to be written. This behavior maybe changed to
                 // return an error in future.
-                Ok(None) => {
+                None => {
                     std::mem::replace(&mut *notify, this.shared.subscribe()).discard();
+
+                    if let Some(e) = this.shared.failure() {
+                        return Poll::Ready(Err(ErrorType::Background(e)));
+                    }
+
                     ready!(notify.as_mut().poll(cx));
                 }
             }
         }
     }
 }
+
+impl Clone for Reader {
+    /// Clone the reader which will have the same read position and map. For
+    /// fresh map, use [`Topic::reader`] or [`Topic::reader_at`] instead.
+    fn clone(&self) -> Self {
+        Self {
+            notify: self.shared.subscribe(),
+            read_at: self.read_at,
+            skip: self.skip,
+            pending: self.pending.clone(),
+            batch: self.batch,
+            error: None,
+            map: self.map.clone(),
+            lookup: None,
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Stream for Reader {
+    type Item = Result<Log>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(e) = self.error.take() {
+            return Poll::Ready(Some(Err(e)));
+        }
+
+        self.poll_log(cx, usize::MAX)
+            .map(|res| res.transpose().map(|res| res.map(|(log, _)| log)))
+    }
+}
+
+/// A finite stream of logs in a UUID range, returned by [`Topic::range`].
+#[derive(Debug)]
+pub struct Range {
+    // Map being reading, `None` if the range is exhausted
+    map: Option<Arc<SharedMap>>,
+    read_at: usize,
+    // Same as `Reader`
+    skip: usize,
+    pending: Pending,
+    // Same as `Reader`
+    lookup: Option<Lookup>,
+    end: Uuid,
+    shared: Arc<Shared>,
+}
+
+impl Range {
+    fn poll_log(&mut self, cx: &mut Context<'_>) -> Poll<Result<Option<Log>>> {
+        while let Some(map) = &self.map {
+            if let Some((at, log)) = self.pending.pop_front() {
+                if log.uuid > self.end {
+                    break;
+                }
+                self.shared.check_truncated(map, at, log.uuid)?;
+                return self
+                    .shared
+                    .decode_body(map.name(), at, map.format(), log)
+                    .map(Some)
+                    .pipe(Poll::Ready);
+            }
+
+            if let Some(lookup) = &mut self.lookup {
+                let next = joined(ready!(Pin::new(lookup).poll(cx)));
+                self.lookup = None;
+                self.map = next?;
+                self.read_at = 0;
+                continue;
+            }
+
+            map.check(self.read_at)?;
+            let finished = map.is_finished();
+            match map.decode(self.read_at)? {
+                Some((mut records, next)) => {
+                    records.drain(..std::mem::take(&mut self.skip).min(records.len()));
+                    self.pending = records;
+                    self.read_at = next;
+                }
+
+                // The active segment is exhausted, don't wait for new logs
+                None if !finished => break,
+
+                None => self.lookup = Some(self.shared.spawn_next_segment(map.name())),
+            }
+        }
+
+        self.map = None;
+        self.pending.clear();
+        Poll::Ready(Ok(None))
+    }
+}
+
+impl Stream for Range {
+    type Item = Result<Log>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_log(cx).map(Result::transpose)
+    }
+}
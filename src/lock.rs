@@ -0,0 +1,71 @@
+//! Lock of a topic directory, so that only one process writes a topic.
+//!
+//! Segments are locked one by one, which doesn't stop two writers from
+//! creating their own segments in the same directory. So the writer locks
+//! `topic.lock` in the topic directory for as long as it runs, and bumps the
+//! fencing epoch in it each time. Read-only topics don't take the lock.
+
+use std::{
+    fs::File,
+    io::{ErrorKind, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use fs2::FileExt;
+use tracing::trace;
+
+use crate::{ErrorType, Result};
+
+/// File locked by the writer, in the topic directory. It holds the fencing
+/// epoch as a u64 LE.
+const LOCK_FILE: &str = "topic.lock";
+
+/// Exclusive lock of a topic directory, released when dropped.
+#[derive(Debug)]
+pub struct DirLock {
+    // The lock is released when the file is closed
+    _file: File,
+    epoch: u64,
+}
+
+impl DirLock {
+    /// Lock topic directory `dir` and bump the fencing epoch. Fails with
+    /// [`ErrorType::Locked`] without waiting if it's locked by another
+    /// writer.
+    pub fn acquire(dir: &Path) -> Result<Self> {
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(dir.join(LOCK_FILE))?;
+
+        match file.try_lock_exclusive() {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                return Err(ErrorType::Locked(dir.to_owned()));
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        // A missing or partially written epoch is taken as 0
+        let mut buf = [0; 8];
+        let epoch = match file.read_exact(&mut buf) {
+            Ok(()) => u64::from_le_bytes(buf) + 1,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => 1,
+            Err(e) => return Err(e.into()),
+        };
+
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&epoch.to_le_bytes())?;
+        file.sync_data()?;
+
+        trace!(?dir, epoch, "Locked topic directory");
+
+        Ok(Self { _file: file, epoch })
+    }
+
+    /// The fencing epoch, greater than that of any writer before.
+    pub const fn epoch(&self) -> u64 {
+        self.epoch
+    }
+}
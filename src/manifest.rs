@@ -0,0 +1,150 @@
+//! Configuration of a topic persisted in its directory.
+//!
+//! The writer stores a [`Manifest`] in `topic.manifest` when the topic is
+//! created, and checks the configuration against it each time the topic is
+//! opened again.
+//!
+//! - `log_size` and `index_size` can't change, since read-only topics map the
+//!   segment being written with them (see [`Topic::open_read_only`]). Opening
+//!   with other values fails with [`ErrorType::ConfigMismatch`].
+//! - Everything else can change. Segments record their own format in their
+//!   headers, so those written before stay readable, and the rest only applies
+//!   while the topic runs. The manifest is updated with the new values.
+//!
+//! Only the settings in [`ManifestConfig`] are stored, with fixed fields so
+//! that adding settings to [`TopicBuilder`] doesn't change the format.
+//! Encryption keys and the archive backend are never stored. Topics created
+//! before manifests get one when they're opened next, with that time as their
+//! creation time.
+//!
+//! [`Topic::open_read_only`]: crate::Topic::open_read_only
+
+use std::{fs, io::ErrorKind, path::Path, time::SystemTime};
+
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{
+    archive::copy_atomic, formats::FormatVersion, Compression, DedupWindow, ErrorType, IndexPolicy,
+    OrderingPolicy, Result, TopicBuilder,
+};
+
+/// File of the [`Manifest`], in the topic directory
+const MANIFEST_FILE: &str = "topic.manifest";
+
+/// Configuration of a topic persisted in its directory, see [module
+/// docs](self).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Version of the manifest format, [`Manifest::VERSION`]
+    pub version: u32,
+    /// When the topic was created
+    pub created: SystemTime,
+    /// Configuration the topic was last opened with for writing
+    pub config: ManifestConfig,
+}
+
+/// Settings of a [`TopicBuilder`] stored in the [`Manifest`]. Fields are only
+/// changed along with [`Manifest::VERSION`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestConfig {
+    pub log_size: u64,
+    pub index_size: u64,
+    /// Format version of new segments
+    pub format_version: FormatVersion,
+    pub record_headers: bool,
+    pub compression: Compression,
+    pub index_policy: IndexPolicy,
+    pub ordering_policy: OrderingPolicy,
+    pub dedup_window: Option<DedupWindow>,
+}
+
+impl From<&TopicBuilder> for ManifestConfig {
+    fn from(conf: &TopicBuilder) -> Self {
+        Self {
+            log_size: conf.log_size,
+            index_size: conf.index_size,
+            format_version: conf.format_version,
+            record_headers: conf.record_headers,
+            compression: conf.compression,
+            index_policy: conf.index_policy,
+            ordering_policy: conf.ordering_policy,
+            dedup_window: conf.dedup_window,
+        }
+    }
+}
+
+impl Manifest {
+    /// Current version of the manifest format
+    pub const VERSION: u32 = 1;
+
+    /// Load the manifest from topic directory `dir`. Returns `None` if
+    /// there's none.
+    pub fn load(dir: &Path) -> Result<Option<Self>> {
+        let bytes = match fs::read(dir.join(MANIFEST_FILE)) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        // Check the version first, the rest may not be decodable otherwise
+        let version: u32 = bincode::deserialize(&bytes)?;
+        if version != Self::VERSION {
+            return Err(ErrorType::Decode(
+                format!("Unsupported manifest version {version}").into(),
+            ));
+        }
+
+        Ok(Some(bincode::deserialize(&bytes)?))
+    }
+
+    /// Check `conf` against the manifest in its topic directory, and persist
+    /// it as the new configuration. Creates the manifest if there's none.
+    pub(crate) fn update(conf: &TopicBuilder) -> Result<Self> {
+        let dir = conf.topic_dir();
+
+        let created = match Self::load(&dir)? {
+            Some(stored) => {
+                stored.check(conf)?;
+                stored.created
+            }
+            None => SystemTime::now(),
+        };
+
+        let this = Self {
+            version: Self::VERSION,
+            created,
+            config: conf.into(),
+        };
+        let bytes = bincode::serialize(&this)?;
+        copy_atomic(&mut bytes.as_slice(), &dir.join(MANIFEST_FILE))?;
+
+        Ok(this)
+    }
+
+    /// Fails with [`ErrorType::ConfigMismatch`] if `conf` changes a setting
+    /// that can't change.
+    fn check(&self, conf: &TopicBuilder) -> Result<()> {
+        let (stored, conf) = (&self.config, ManifestConfig::from(conf));
+        let fixed = [
+            ("log_size", stored.log_size, conf.log_size),
+            ("index_size", stored.index_size, conf.index_size),
+        ];
+
+        for (field, stored, configured) in fixed {
+            if stored != configured {
+                return Err(ErrorType::ConfigMismatch {
+                    field,
+                    stored,
+                    configured,
+                });
+            }
+        }
+
+        if stored != &conf {
+            info!(?stored, configured = ?conf, "Topic configuration changed");
+        }
+
+        Ok(())
+    }
+}
@@ -1,81 +1,225 @@
-use std::{fs::File, mem::ManuallyDrop, path::Path};
+use std::{
+    fs::File,
+    io::{Error as IoError, ErrorKind as IoErrorKind, Read},
+    mem::ManuallyDrop,
+    ops::Deref,
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
+use arc_swap::ArcSwap;
 use fs2::FileExt;
 use memmap2::{MmapOptions, MmapRaw};
 use tap::{Pipe, Tap};
 use tracing::trace;
 
-use crate::{consts::HEADER_SIZE, error::Result, formats::Header};
+use crate::{
+    consts::HEADER_SIZE,
+    error::Result,
+    formats::{Attributes, Header},
+};
 
 /// A wrapper for [`MmapRaw`], with a 16-byte header.
+///
+/// With a `chunk`, the file grows on demand by chunks of that size with
+/// [`reserve`](RawMap::reserve) instead of being preallocated, up to `size`.
+/// Only the file is mapped, so it's mapped again each time it grows. Slices
+/// keep the mapping they're in alive, see [`MapSlice`].
 #[derive(Debug)]
 pub struct RawMap {
-    raw: ManuallyDrop<MmapRaw>,
+    raw: ManuallyDrop<ArcSwap<MmapRaw>>,
     file: File,
+    read_only: bool,
+    /// Max length excluding the header
+    size: usize,
+    /// Length of the file excluding the header. Only less than `size` when
+    /// growing on demand.
+    file_len: AtomicUsize,
+    chunk: Option<usize>,
+}
+
+/// Bytes of a [`RawMap`]. The mapping they're in is kept until it's dropped,
+/// even if the map is mapped again meanwhile.
+#[derive(Debug)]
+pub struct MapSlice<'a> {
+    _raw: Arc<MmapRaw>,
+    slice: &'a [u8],
+}
+
+impl Deref for MapSlice<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.slice
+    }
 }
 
 impl RawMap {
-    pub(crate) fn new(path: &Path, size: u64, header: Header) -> Result<Self> {
-        trace!(?path, size, "Opening mmap");
+    pub(crate) fn new(path: &Path, size: u64, chunk: Option<u64>, header: Header) -> Result<Self> {
+        trace!(?path, size, chunk, "Opening mmap");
 
+        let file_len = chunk.map_or(size, |chunk| chunk.min(size));
         let file = File::options()
             .read(true)
             .write(true)
             .create(true)
             .open(path)?;
         file.try_lock_exclusive()?;
-        file.set_len(size + HEADER_SIZE as u64)?;
-        let raw = MmapOptions::new().map_raw(&file)?.pipe(ManuallyDrop::new);
-        let this = Self { raw, file };
-        this.write_header(header);
+        file.set_len(file_len + HEADER_SIZE as u64)?;
+        let raw = MmapOptions::new().map_raw(&file)?;
+        let this = Self {
+            raw: ManuallyDrop::new(ArcSwap::from_pointee(raw)),
+            file,
+            read_only: false,
+            size: size as _,
+            file_len: AtomicUsize::new(file_len as _),
+            chunk: chunk.map(|chunk| chunk.max(1) as _),
+        };
+        this.write_header(header.tap_mut(|h| {
+            h.set_attributes(h.attributes().tap_mut(|a| {
+                a.committed = 0;
+                a.flags |= Attributes::WATERMARK;
+            }));
+        }));
+
         Ok(this)
     }
 
-    pub fn advice_write(&self, _offset: usize, _len: usize) -> Result<()> {
-        // #[cfg(unix)]
-        // self.raw.advise_range(Advice:: offset, len).map_err(Into::into)
-        todo!()
+    /// Open an existing file read-only, with a shared lock. Blocks while the
+    /// file is locked exclusively, i.e. still written or repaired. The file
+    /// must start with a header with the same magic number as `header`.
+    pub(crate) fn open(path: &Path, header: Header) -> Result<Self> {
+        trace!(?path, "Opening read-only mmap");
+
+        let file = File::open(path)?;
+        file.lock_shared()?;
+        let raw: MmapRaw = unsafe { MmapOptions::new().map(&file)? }.into();
+        check_magic(&raw, header, path)?;
+
+        Ok(Self::with_raw(raw, file, true))
     }
 
-    pub fn flush(&self) -> Result<()> {
-        self.raw.flush_async().map_err(Into::into)
+    /// Open a file being written by another process read-only, without a
+    /// lock so the writer keeps its exclusive one. Only the committed length
+    /// in the header is safe to read. It may grow up to `size`, see
+    /// [`remap`](RawMap::remap). Fails with [`IoErrorKind::WouldBlock`] if the
+    /// header is not written yet.
+    pub(crate) fn open_live(path: &Path, size: u64, header: Header) -> Result<Self> {
+        trace!(?path, size, "Opening live mmap");
+
+        // The file is created before its header is written
+        let mut file = File::open(path)?;
+        let mut buf = [0; HEADER_SIZE];
+        if file.read_exact(&mut buf).is_err() || buf[..8] != header.magic_number {
+            return IoError::new(
+                IoErrorKind::WouldBlock,
+                format!("Header not written yet: {}", path.display()),
+            )
+            .pipe(|e| Err(e.into()));
+        }
+
+        let raw: MmapRaw = unsafe { MmapOptions::new().map(&file)? }.into();
+
+        Ok(Self::with_raw(raw, file, true).tap_mut(|this| this.size = this.size.max(size as _)))
     }
 
-    pub fn flush_sync(&self) -> Result<()> {
-        self.raw.flush().map_err(Into::into)
+    /// Open an existing file for writing, with an exclusive lock. Fails if the
+    /// file is in use. The file must start with a header with the same magic
+    /// number as `header`.
+    pub(crate) fn open_mut(path: &Path, header: Header) -> Result<Self> {
+        trace!(?path, "Opening mmap for writing");
+
+        let file = File::options().read(true).write(true).open(path)?;
+        file.try_lock_exclusive()?;
+        let raw = MmapOptions::new().map_raw(&file)?;
+        check_magic(&raw, header, path)?;
+
+        Ok(Self::with_raw(raw, file, false))
     }
 
-    pub fn flush_range(&self, offset: usize, len: usize) -> Result<()> {
-        self.raw.flush_async_range(offset, len).map_err(Into::into)
+    /// Wrap `raw` mapping the whole `file`, which doesn't grow
+    fn with_raw(raw: MmapRaw, file: File, read_only: bool) -> Self {
+        let len = raw.len() - HEADER_SIZE;
+
+        Self {
+            raw: ManuallyDrop::new(ArcSwap::from_pointee(raw)),
+            file,
+            read_only,
+            size: len,
+            file_len: AtomicUsize::new(len),
+            chunk: None,
+        }
+    }
+
+    /// Make sure the file backs the mmap up to `end`, growing it by chunks and
+    /// mapping it again if needed. Only one writer may call this at a time.
+    pub fn reserve(&self, end: usize) -> Result<()> {
+        let Some(chunk) = self.chunk else { return Ok(()) };
+        if end <= self.file_len.load(Ordering::Acquire) {
+            return Ok(());
+        }
+
+        let len = ((end + chunk - 1) / chunk * chunk).min(self.len());
+        trace!(len, "Growing mmap");
+
+        self.file.set_len((len + HEADER_SIZE) as u64)?;
+        self.raw
+            .store(Arc::new(MmapOptions::new().map_raw(&self.file)?));
+        self.file_len.store(len, Ordering::Release);
+
+        Ok(())
     }
 
-    pub const fn file(&self) -> &File {
-        &self.file
+    /// Map the file again if it grew and the mapping doesn't cover `end`.
+    /// Returns the length mapped, which may still be less than `end` if the
+    /// file is shorter. For files written by another process, see
+    /// [`open_live`](RawMap::open_live).
+    pub fn remap(&self, end: usize) -> Result<usize> {
+        let mapped = self.mapped_len();
+        if end <= mapped {
+            return Ok(mapped);
+        }
+
+        let raw: MmapRaw = unsafe { MmapOptions::new().map(&self.file)? }.into();
+        let len = raw.len() - HEADER_SIZE;
+        trace!(len, "Remapping mmap");
+
+        self.raw.store(Arc::new(raw));
+        self.file_len.store(len, Ordering::Release);
+
+        Ok(len)
     }
 
-    pub fn len(&self) -> usize {
-        // Offset by size of header
-        self.raw.len() - HEADER_SIZE
+    pub fn flush_sync(&self) -> Result<()> {
+        let raw = self.raw.load();
+        raw.flush_range(0, raw.len()).map_err(Into::into)
     }
 
-    pub fn is_empty(&self) -> bool {
-        self.len() == 0
+    /// Flush `len` bytes at `offset` after the header
+    pub fn flush_range(&self, offset: usize, len: usize) -> Result<()> {
+        self.raw
+            .load()
+            .flush_async_range(offset + HEADER_SIZE, len)
+            .map_err(Into::into)
     }
 
-    pub fn as_ptr(&self) -> *const u8 {
-        // Offset by size of header
-        unsafe { self.raw.as_ptr().add(HEADER_SIZE) }
+    /// Max length of the file, excluding the header
+    pub const fn len(&self) -> usize {
+        self.size
     }
 
-    pub fn as_mut_ptr(&self) -> *mut u8 {
-        // Offset by size of header
-        unsafe { self.raw.as_mut_ptr().add(HEADER_SIZE) }
+    /// Length of the current mapping, excluding the header
+    pub fn mapped_len(&self) -> usize {
+        self.raw.load().len() - HEADER_SIZE
     }
 
     #[allow(clippy::missing_panics_doc)]
     pub fn load_header(&self) -> Header {
         Header::from_bytes(unsafe {
-            &std::slice::from_raw_parts(self.raw.as_ptr(), HEADER_SIZE)
+            &std::slice::from_raw_parts(self.raw.load().as_ptr(), HEADER_SIZE)
                 .try_into()
                 .unwrap()
         })
@@ -87,22 +231,60 @@ impl RawMap {
         self.write_header(header);
     }
 
+    /// Returns the committed length of the file. It's the watermark in the
+    /// header if maintained, which may be less than the file length if the
+    /// file was not closed properly.
+    pub fn committed(&self) -> usize {
+        self.load_header()
+            .attributes()
+            .watermark()
+            .map_or(self.len(), |committed| (committed as usize).min(self.len()))
+    }
+
+    /// Wait for the bytes written up to `len` to reach the disk, then persist
+    /// `len` as the committed length to the header, so the watermark never
+    /// points at unwritten data. Only the writer may call this.
+    pub fn sync(&self, len: usize) -> Result<()> {
+        let synced = self.committed();
+        if len <= synced {
+            return Ok(());
+        }
+
+        let raw = self.raw.load();
+        raw.flush_range(synced + HEADER_SIZE, len - synced)?;
+        self.update_header(|h| {
+            h.set_attributes(h.attributes().tap_mut(|a| a.committed = len as _));
+        });
+        raw.flush_async_range(0, HEADER_SIZE).map_err(Into::into)
+    }
+
     /// Write the header to the mmap
     fn write_header(&self, header: Header) {
-        unsafe { header.write_to(std::slice::from_raw_parts_mut(self.raw.as_mut_ptr(), 16)) }
+        unsafe {
+            header.write_to(std::slice::from_raw_parts_mut(
+                self.raw.load().as_mut_ptr(),
+                HEADER_SIZE,
+            ));
+        }
     }
 
     /// # Safety
-    /// Caller must ensure that offset is less than length of the mmap
-    pub unsafe fn range(&self, offset: usize, len: usize) -> &[u8] {
-        std::slice::from_raw_parts(self.as_ptr().add(offset), len)
+    /// Caller must ensure that `offset + len` is not greater than the length
+    /// mapped, and the bytes are not written meanwhile
+    pub unsafe fn range(&self, offset: usize, len: usize) -> MapSlice<'_> {
+        let raw = self.raw.load_full();
+        let slice = std::slice::from_raw_parts(raw.as_ptr().add(HEADER_SIZE + offset), len);
+
+        MapSlice { _raw: raw, slice }
     }
 
     /// # Safety
-    /// Caller must ensure that access to the slice is exclusive and offset is
-    /// less than length of the mmap
-    pub unsafe fn range_mut(&mut self, offset: usize, len: usize) -> &mut [u8] {
-        std::slice::from_raw_parts_mut(self.as_mut_ptr().add(offset), len)
+    /// Caller must be the only writer, ensure that `offset + len` is not
+    /// greater than the length mapped, and not [`reserve`](RawMap::reserve)
+    /// while the slice is used
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn range_mut(&self, offset: usize, len: usize) -> &mut [u8] {
+        std::slice::from_raw_parts_mut(self.raw.load().as_mut_ptr().add(HEADER_SIZE + offset), len)
     }
 
     /// Like `Drop`, but close the file with specified length. This is intended
@@ -115,12 +297,44 @@ impl RawMap {
     pub unsafe fn close(&mut self, final_len: u64) -> Result<()> {
         trace!(final_len, map = ?self, "Closing mmap");
 
+        // Nothing was written to a read-only map. No slice is left, so dropping the
+        // last `ArcSwap` unmaps the file.
+        if self.read_only {
+            ManuallyDrop::drop(&mut self.raw);
+            return self.file.unlock().map_err(Into::into);
+        }
+
+        self.update_header(|h| {
+            h.set_attributes(h.attributes().tap_mut(|a| {
+                a.committed = final_len;
+                a.flags |= Attributes::WATERMARK | Attributes::CLEAN;
+            }));
+        });
+
         // Unlock and truncate even if flush failed
-        self.raw
-            .flush()
+        self.flush_sync()
             .tap(|_| ManuallyDrop::drop(&mut self.raw)) // Drop the mmap before unlocking the file so that windows won't complain
-            .and(self.file.set_len(final_len + HEADER_SIZE as u64))
-            .and(self.file.unlock())
-            .map_err(Into::into)
+            .and(
+                self.file
+                    .set_len(final_len + HEADER_SIZE as u64)
+                    .map_err(Into::into),
+            )
+            .and(self.file.unlock().map_err(Into::into))
+    }
+}
+
+fn check_magic(raw: &MmapRaw, header: Header, path: &Path) -> Result<()> {
+    let valid = raw.len() >= HEADER_SIZE
+        && unsafe { std::slice::from_raw_parts(raw.as_ptr(), header.magic_number.len()) }
+            == header.magic_number;
+
+    if valid {
+        Ok(())
+    } else {
+        IoError::new(
+            IoErrorKind::InvalidData,
+            format!("Invalid header: {}", path.display()),
+        )
+        .pipe(|e| Err(e.into()))
     }
 }
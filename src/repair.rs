@@ -0,0 +1,328 @@
+//! Recovery of segments that were not closed properly.
+//!
+//! A writer that crashed may leave a segment with a partially written record
+//! at its end, or with an `.idx` that points past the valid records. Such
+//! segments are repaired with [`recover`] when a topic is opened, and
+//! [`repair_segment`] can be used to check and repair a segment manually.
+//! An `.idx` that is missing or doesn't cover all records is regenerated from
+//! the `.limlog` with [`rebuild_index`].
+
+use std::{
+    cmp::Ordering,
+    fs::{self, File},
+    io::{ErrorKind, Read, Write},
+    path::Path,
+};
+
+use tap::Tap;
+use tracing::warn;
+use uuid7::Uuid;
+
+use crate::{
+    archive::SegmentFile,
+    consts::{HEADER_SIZE, INDEX_SIZE},
+    formats::{Attributes, FormatVersion, Header, RecordFormat, UuidIndex},
+    inner::list_segments,
+    raw::RawMap,
+    ErrorType, Result,
+};
+
+/// What was discarded by [`repair_segment`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RepairReport {
+    /// Name of the segment
+    pub segment: String,
+    /// Length of valid records in the `.limlog`, which the file is truncated
+    /// to
+    pub valid_len: u64,
+    /// Bytes after the last valid record that were committed, i.e. the torn
+    /// or corrupted part
+    pub discarded_bytes: u64,
+    /// Entries in the `.idx` that don't match a valid record
+    pub discarded_indexes: u64,
+    /// If the `.idx` was rebuilt since it didn't cover all valid records
+    pub index_rebuilt: bool,
+}
+
+impl RepairReport {
+    /// If nothing was discarded.
+    pub const fn is_intact(&self) -> bool {
+        self.discarded_bytes == 0 && self.discarded_indexes == 0
+    }
+}
+
+/// Repair all segments in topic directory `dir` that were not closed
+/// properly, and rebuild indexes that don't match their logs. Returns reports
+/// of the repaired segments.
+pub fn recover(dir: &Path) -> Result<Vec<RepairReport>> {
+    let mut reports = vec![];
+
+    for name in list_segments(dir)? {
+        if let Some(report) = recover_segment(dir, &name)? {
+            reports.push(report);
+        }
+    }
+
+    Ok(reports)
+}
+
+fn recover_segment(dir: &Path, name: &str) -> Result<Option<RepairReport>> {
+    let path = dir.join(name).with_extension(SegmentFile::Log.extension());
+    if is_clean(&path)? {
+        if !index_matches(dir, name)? {
+            warn!(name, "Index doesn't match the log, rebuilding");
+            rebuild_index(dir, name)?;
+        }
+        return Ok(None);
+    }
+
+    let report = match repair_segment(dir, name) {
+        Ok(report) => report,
+        // Being written by someone else
+        Err(ErrorType::Io(e)) if e.kind() == ErrorKind::WouldBlock => {
+            warn!(name, "Segment is in use, skip repairing");
+            return Ok(None);
+        }
+        Err(e) => return Err(e),
+    };
+    if !report.is_intact() {
+        warn!(?report, "Discarded torn writes");
+    }
+
+    Ok(Some(report))
+}
+
+/// Find the last valid record of segment `name` in `dir`, and truncate the
+/// `.limlog` and `.idx` after it. Index entries that don't match a valid
+/// record are discarded too. The segment must not be in use.
+///
+/// Records are valid up to the first one that doesn't match its index entry.
+/// Of the records without an entry, empty logs with nil UUIDs and UUIDs that
+/// go backwards are treated as torn, so out of order logs written with
+/// [`OrderingPolicy::Allow`](crate::OrderingPolicy::Allow) may be discarded if
+/// they are not indexed yet.
+#[allow(clippy::missing_panics_doc)]
+pub fn repair_segment(dir: &Path, name: &str) -> Result<RepairReport> {
+    let path = dir.join(name);
+
+    let mut log = RawMap::open_mut(
+        &path.with_extension(SegmentFile::Log.extension()),
+        Header::LOG,
+    )?;
+    let mut idx = match RawMap::open_mut(
+        &path.with_extension(SegmentFile::Index.extension()),
+        Header::INDEX,
+    ) {
+        Ok(idx) => Some(idx),
+        Err(ErrorType::Io(e)) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+
+    let committed = log.committed();
+    let format = record_format(&log);
+    let indexes = idx.as_ref().map_or(0, |idx| idx.committed() / INDEX_SIZE);
+
+    // SAFETY: The map is locked exclusively
+    let data = unsafe { log.range(0, committed) };
+    let index_at = |i: usize| {
+        let idx = idx.as_ref().unwrap();
+        UuidIndex::from_bytes(
+            unsafe { idx.range(i * INDEX_SIZE, INDEX_SIZE) }[..]
+                .try_into()
+                .unwrap(),
+        )
+    };
+
+    // Walk records and index entries together. Entries must point to records in
+    // order, with the same UUID. Batches are walked as a whole, and indexed by
+    // their first records.
+    let (mut valid, mut last, mut records, mut matched, mut prev) = (0, 0, 0, 0, Uuid::NIL);
+    'walk: while let Ok(Some((batch, len))) = format.decode_batch(&data[valid..]) {
+        let mut confirmed = false;
+        while matched < indexes {
+            let index = index_at(matched);
+            match (index.offset as usize).cmp(&valid) {
+                Ordering::Greater => break,
+                Ordering::Equal if index.uuid == batch[0].1.uuid => {
+                    matched += 1;
+                    confirmed = true;
+                }
+                // The index saw another record here, so the log is torn from
+                // here on
+                Ordering::Equal => break 'walk,
+                // The index saw a record in the middle of the last batch
+                Ordering::Less => {
+                    if matched > 0 && index_at(matched - 1).offset as usize == last {
+                        matched -= 1;
+                    }
+                    (valid, records) = (last, records - 1);
+                    break 'walk;
+                }
+            }
+        }
+
+        // A zeroed tail inside the watermark decodes as empty logs, and UUIDs
+        // that go backwards are most likely garbage, unless indexed
+        let torn = batch
+            .iter()
+            .any(|(_, log)| log.uuid == Uuid::NIL && log.body.is_empty() && log.headers.is_empty())
+            || batch[0].1.uuid < prev;
+        if torn && !confirmed {
+            break;
+        }
+
+        prev = batch.last().unwrap().1.uuid;
+        last = valid;
+        valid += len as usize;
+        records += 1;
+    }
+
+    let mut report = RepairReport {
+        segment: name.to_owned(),
+        valid_len: valid as _,
+        discarded_bytes: (committed - valid) as _,
+        discarded_indexes: (indexes - matched) as _,
+        index_rebuilt: false,
+    };
+
+    let sparse = idx.as_ref().map_or(false, is_sparse);
+
+    // SAFETY: Maps are closed only once here
+    unsafe {
+        log.close(valid as _)?;
+        if let Some(idx) = &mut idx {
+            idx.close((matched * INDEX_SIZE) as _)?;
+        }
+    }
+
+    // The index is flushed separately from the log, so it may miss the last
+    // records, or it may be gone entirely. Sparse indexes are not expected to
+    // cover every record.
+    if !sparse && matched < records {
+        rebuild_index(dir, name)?;
+        report.index_rebuilt = true;
+    }
+
+    Ok(report)
+}
+
+/// Rebuild the `.idx` of segment `name` in `dir` by scanning its `.limlog`,
+/// replacing the existing one if any. Returns the number of entries written.
+///
+/// Blocks if the segment is still being written.
+pub fn rebuild_index(dir: &Path, name: &str) -> Result<u64> {
+    let path = dir.join(name);
+    let mut log = RawMap::open(
+        &path.with_extension(SegmentFile::Log.extension()),
+        Header::LOG,
+    )?;
+
+    // SAFETY: The map is locked, so committed data won't change
+    let data = unsafe { log.range(0, log.committed()) };
+    let format = record_format(&log);
+
+    let mut entries = vec![];
+    let mut offset = 0;
+    while let Some((batch, len)) = format.decode_batch(&data[offset..])? {
+        entries.extend(
+            UuidIndex {
+                uuid: batch[0].1.uuid,
+                offset: offset as _,
+            }
+            .as_bytes(),
+        );
+        offset += len as usize;
+    }
+
+    let mut flags = Attributes::WATERMARK | Attributes::CLEAN;
+    if format.version == FormatVersion::V2 {
+        flags |= Attributes::SPARSE;
+    }
+    let header = Header::INDEX.tap_mut(|h| {
+        h.set_attributes(Attributes {
+            committed: entries.len() as _,
+            flags,
+            key_id: 0,
+        });
+    });
+
+    // Write to a temporary file first so the index is never partially written
+    let idx = path.with_extension(SegmentFile::Index.extension());
+    let tmp = path.with_extension(format!("{}.tmp", SegmentFile::Index.extension()));
+    let mut file = File::create(&tmp)?;
+    file.write_all(&header.as_bytes())?;
+    file.write_all(&entries)?;
+    file.sync_all()?;
+    fs::rename(tmp, idx)?;
+
+    // SAFETY: The map is closed only once here
+    unsafe { log.close(0)? };
+
+    Ok((entries.len() / INDEX_SIZE) as _)
+}
+
+/// If the last entry of the `.idx` points to a record of the `.limlog`, which
+/// must be the last one unless the index is sparse.
+fn index_matches(dir: &Path, name: &str) -> Result<bool> {
+    let path = dir.join(name);
+
+    let mut idx = match RawMap::open(
+        &path.with_extension(SegmentFile::Index.extension()),
+        Header::INDEX,
+    ) {
+        Ok(idx) => idx,
+        Err(ErrorType::Io(e)) if e.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    let mut log = RawMap::open(
+        &path.with_extension(SegmentFile::Log.extension()),
+        Header::LOG,
+    )?;
+
+    let entries = idx.committed() / INDEX_SIZE;
+    let committed = log.committed();
+
+    let matches = if entries == 0 {
+        committed == 0
+    } else {
+        // SAFETY: Both maps are locked, and ranges are in committed data
+        let last = UuidIndex::from_bytes(
+            unsafe { idx.range((entries - 1) * INDEX_SIZE, INDEX_SIZE) }[..]
+                .try_into()
+                .unwrap(),
+        );
+        let offset = (last.offset as usize).min(committed);
+
+        match record_format(&log).decode_batch(&unsafe { log.range(offset, committed - offset) }) {
+            Ok(Some((batch, len))) => {
+                batch[0].1.uuid == last.uuid
+                    && (is_sparse(&idx) || offset + len as usize == committed)
+            }
+            _ => false,
+        }
+    };
+
+    // SAFETY: Maps are closed only once here
+    unsafe {
+        idx.close(0)?;
+        log.close(0)?;
+    }
+
+    Ok(matches)
+}
+
+fn record_format(log: &RawMap) -> RecordFormat {
+    RecordFormat::from_attributes(log.load_header().attributes())
+}
+
+fn is_sparse(idx: &RawMap) -> bool {
+    idx.load_header().attributes().has(Attributes::SPARSE)
+}
+
+/// If the file was closed properly, according to its header
+fn is_clean(path: &Path) -> Result<bool> {
+    let mut buf = [0; HEADER_SIZE];
+    File::open(path)?.read_exact(&mut buf)?;
+
+    Ok(Header::from_bytes(&buf).attributes().has(Attributes::CLEAN))
+}
@@ -0,0 +1,397 @@
+//! Leader/follower replication over a byte stream.
+//!
+//! A [`Leader`] streams committed bytes of its segments, as they are laid out
+//! in the `.limlog` file, to a [`Follower`] over any
+//! [`AsyncRead`] + [`AsyncWrite`] transport. The follower decodes the records
+//! and appends them to its own topic, keeping their UUIDs. Segments of the
+//! follower roll according to its own configuration. The follower subscribes
+//! with the UUID of the last log in its topic, so it resumes where it stopped
+//! even after a restart.
+//!
+//! ```ignore
+//! let (a, b) = tokio::io::duplex(1 << 16);
+//!
+//! tokio::spawn(async move { leader_topic.leader().serve(a).await });
+//!
+//! let follower = follower_topic.follower();
+//! follower.replicate(b).await?;
+//! ```
+
+use std::sync::{Arc, Mutex};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    task::spawn_blocking,
+};
+use tracing::trace;
+use uuid7::Uuid;
+
+use crate::{
+    formats::{Attributes, RecordFormat},
+    inner::{joined, Origin, Shared},
+    ErrorType, Result, Writer,
+};
+
+/// Leader -> follower, committed bytes of a segment
+const RECORDS: u8 = 1;
+
+/// Leader -> follower, the segment being replicated is finished
+const ROLL: u8 = 2;
+
+/// Follower -> leader, the UUID of the last log the follower has
+const SUBSCRIBE: u8 = 3;
+
+/// Maximum number of bytes sent in one [`RECORDS`] frame
+const MAX_CHUNK: usize = 1 << 20;
+
+/// The leader side of replication, obtained by [`Topic::leader`].
+///
+/// [`Topic::leader`]: crate::Topic::leader
+#[derive(Debug, Clone)]
+pub struct Leader {
+    shared: Arc<Shared>,
+}
+
+impl Leader {
+    pub(crate) const fn new(shared: Arc<Shared>) -> Self {
+        Self { shared }
+    }
+
+    /// Serve one follower over `stream`.
+    ///
+    /// Replication resumes from the records containing the last log the
+    /// follower has, or starts from the oldest log kept for a new follower.
+    /// Returns when the follower disconnects or the background task of the
+    /// topic failed.
+    pub async fn serve<S: AsyncRead + AsyncWrite + Unpin + Send>(
+        &self,
+        mut stream: S,
+    ) -> Result<()> {
+        let last = match read_frame(&mut stream).await? {
+            Some(Frame::Subscribe { last }) => last,
+            Some(_) => return Err(ErrorType::Protocol("Expected subscribe frame".to_owned())),
+            None => return Ok(()),
+        };
+
+        // Archived segments may be downloaded
+        let shared = self.shared.clone();
+        let (mut map, mut pos, _) = joined(spawn_blocking(move || shared.seek(last)).await)?;
+
+        trace!(%last, segment = map.name(), pos, "Follower subscribed");
+
+        loop {
+            // Subscribe before checking for new data so no notification is missed
+            let listener = self.shared.subscribe();
+
+            // The follower can't drop logs it has, so stop if they're truncated
+            map.check(pos)?;
+            // Load this before the slice, the last logs may be committed in between
+            let finished = map.is_finished();
+            let slice = map.slice(pos);
+
+            if !slice.is_empty() {
+                let chunk = &slice[..slice.len().min(MAX_CHUNK)];
+                let frame = Frame::Records {
+                    segment: map.name().to_owned(),
+                    offset: pos as _,
+                    head: map.offset() as _,
+                    format: map.format(),
+                    bytes: chunk.to_vec(),
+                };
+                frame.write_to(&mut stream).await?;
+                pos += chunk.len();
+                continue;
+            }
+
+            if finished {
+                // Same as `Reader`, no segment after a finished one means the background task
+                // won't make more progress
+                let Some(next) = joined(self.shared.spawn_next_segment(map.name()).await)? else {
+                    return self
+                        .shared
+                        .failure()
+                        .map_or(Ok(()), |e| Err(ErrorType::Background(e)));
+                };
+
+                Frame::Roll {
+                    segment: next.name().to_owned(),
+                }
+                .write_to(&mut stream)
+                .await?;
+
+                map = next;
+                pos = 0;
+                continue;
+            }
+
+            if let Some(e) = self.shared.failure() {
+                return Err(ErrorType::Background(e));
+            }
+
+            listener.await;
+        }
+    }
+}
+
+/// The follower side of replication, obtained by [`Topic::follower`].
+///
+/// [`Topic::follower`]: crate::Topic::follower
+#[derive(Debug, Clone)]
+pub struct Follower {
+    writer: Writer,
+    lag: Arc<Mutex<ReplicationLag>>,
+}
+
+/// Replication progress of a [`Follower`], in terms of the leader segment
+/// being replicated.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReplicationLag {
+    /// Name of the leader segment being replicated
+    pub segment: String,
+    /// Offset in the leader segment up to which records have been appended
+    pub applied: u64,
+    /// Committed offset of the leader segment as of the last received frame
+    pub head: u64,
+}
+
+impl ReplicationLag {
+    /// Number of committed bytes in the leader segment that are not appended
+    /// yet.
+    pub const fn bytes(&self) -> u64 {
+        self.head.saturating_sub(self.applied)
+    }
+}
+
+impl Follower {
+    pub(crate) fn new(writer: Writer) -> Self {
+        Self {
+            writer,
+            lag: Arc::default(),
+        }
+    }
+
+    /// Returns the current replication progress.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn lag(&self) -> ReplicationLag {
+        self.lag.lock().unwrap().clone()
+    }
+
+    /// Replicate from a leader over `stream` until the leader disconnects.
+    ///
+    /// This can be called again with a new stream, or by a follower of the
+    /// reopened topic, to resume replication after the last log written.
+    #[allow(clippy::missing_panics_doc)]
+    pub async fn replicate<S: AsyncRead + AsyncWrite + Unpin + Send>(
+        &self,
+        mut stream: S,
+    ) -> Result<()> {
+        let shared = self.writer.shared.clone();
+        let mut last =
+            joined(spawn_blocking(move || shared.newest_uuid()).await)?.unwrap_or(Uuid::NIL);
+
+        Frame::Subscribe { last }.write_to(&mut stream).await?;
+
+        // The leader resumes from the start of a segment or records
+        *self.lag.lock().unwrap() = ReplicationLag::default();
+
+        // Bytes of a record that is not fully received yet
+        let mut buf = Vec::new();
+
+        while let Some(frame) = read_frame(&mut stream).await? {
+            match frame {
+                Frame::Records {
+                    segment,
+                    offset,
+                    head,
+                    format,
+                    bytes,
+                } => {
+                    let (segment, applied) = {
+                        let mut lag = self.lag.lock().unwrap();
+
+                        if lag.segment != segment {
+                            if !buf.is_empty() {
+                                return Err(ErrorType::Protocol(
+                                    "Segment changed in the middle of a record".to_owned(),
+                                ));
+                            }
+                            *lag = ReplicationLag {
+                                segment,
+                                applied: offset,
+                                head,
+                            };
+                        }
+
+                        if offset != lag.applied + buf.len() as u64 {
+                            return Err(ErrorType::Protocol(format!(
+                                "Expected offset {}, got {offset}",
+                                lag.applied + buf.len() as u64
+                            )));
+                        }
+
+                        lag.head = head;
+                        (lag.segment.clone(), lag.applied)
+                    };
+
+                    buf.extend_from_slice(&bytes);
+
+                    let mut read = 0;
+                    while let Some((records, len)) = format.decode_batch(&buf[read..])? {
+                        // Encoded in the format of the leader segment, and encoded again in
+                        // the format of the follower when written
+                        for (at, record) in records {
+                            // The records the follower stopped in are sent again
+                            if record.uuid <= last {
+                                continue;
+                            }
+                            let at = applied as usize + read + at as usize;
+                            let log = self
+                                .writer
+                                .shared
+                                .decode_body(&segment, at, format, record)?;
+                            last = self.writer.send(log, Origin::Replicated).await?;
+                        }
+                        read += len as usize;
+                    }
+                    buf.drain(..read);
+
+                    self.lag.lock().unwrap().applied = applied + read as u64;
+                }
+                Frame::Roll { segment } => {
+                    if !buf.is_empty() {
+                        return Err(ErrorType::Protocol(
+                            "Segment rolled in the middle of a record".to_owned(),
+                        ));
+                    }
+
+                    trace!(segment, "Leader rolled");
+
+                    *self.lag.lock().unwrap() = ReplicationLag {
+                        segment,
+                        ..Default::default()
+                    };
+                }
+                Frame::Subscribe { .. } => {
+                    return Err(ErrorType::Protocol("Unexpected subscribe frame".to_owned()));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+enum Frame {
+    Records {
+        segment: String,
+        offset: u64,
+        head: u64,
+        format: RecordFormat,
+        bytes: Vec<u8>,
+    },
+    Roll {
+        segment: String,
+    },
+    Subscribe {
+        last: Uuid,
+    },
+}
+
+impl Frame {
+    async fn write_to<W: AsyncWrite + Unpin + Send>(&self, w: &mut W) -> Result<()> {
+        match self {
+            Self::Records {
+                segment,
+                offset,
+                head,
+                format,
+                bytes,
+            } => {
+                w.write_u8(RECORDS).await?;
+                write_str(w, segment).await?;
+                w.write_u64_le(*offset).await?;
+                w.write_u64_le(*head).await?;
+                let attr = format.attributes();
+                w.write_u8(attr.flags).await?;
+                w.write_u8(attr.key_id).await?;
+                w.write_u32_le(bytes.len() as _).await?;
+                w.write_all(bytes).await?;
+            }
+            Self::Roll { segment } => {
+                w.write_u8(ROLL).await?;
+                write_str(w, segment).await?;
+            }
+            Self::Subscribe { last } => {
+                w.write_u8(SUBSCRIBE).await?;
+                w.write_all(last.as_bytes()).await?;
+            }
+        }
+
+        w.flush().await?;
+
+        Ok(())
+    }
+}
+
+/// Read a frame, returns `None` if the stream is closed between frames.
+async fn read_frame<R: AsyncRead + Unpin + Send>(r: &mut R) -> Result<Option<Frame>> {
+    let tag = match r.read_u8().await {
+        Ok(tag) => tag,
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let frame = match tag {
+        RECORDS => {
+            let segment = read_str(r).await?;
+            let offset = r.read_u64_le().await?;
+            let head = r.read_u64_le().await?;
+            let format = RecordFormat::from_attributes(Attributes {
+                committed: 0,
+                flags: r.read_u8().await?,
+                key_id: r.read_u8().await?,
+            });
+            let len = r.read_u32_le().await? as usize;
+            if len > MAX_CHUNK {
+                return Err(ErrorType::Protocol(format!("Frame too large: {len}")));
+            }
+            let mut bytes = vec![0; len];
+            r.read_exact(&mut bytes).await?;
+
+            Frame::Records {
+                segment,
+                offset,
+                head,
+                format,
+                bytes,
+            }
+        }
+        ROLL => Frame::Roll {
+            segment: read_str(r).await?,
+        },
+        SUBSCRIBE => {
+            let mut last = [0; 16];
+            r.read_exact(&mut last).await?;
+            Frame::Subscribe {
+                last: Uuid::from(last),
+            }
+        }
+        tag => return Err(ErrorType::Protocol(format!("Unknown frame tag {tag}"))),
+    };
+
+    Ok(Some(frame))
+}
+
+async fn write_str<W: AsyncWrite + Unpin + Send>(w: &mut W, s: &str) -> Result<()> {
+    w.write_u16_le(s.len() as _).await?;
+    w.write_all(s.as_bytes()).await?;
+    Ok(())
+}
+
+async fn read_str<R: AsyncRead + Unpin + Send>(r: &mut R) -> Result<String> {
+    let len = r.read_u16_le().await? as usize;
+    let mut buf = vec![0; len];
+    r.read_exact(&mut buf).await?;
+    String::from_utf8(buf).map_err(|e| ErrorType::Protocol(e.to_string()))
+}
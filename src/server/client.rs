@@ -0,0 +1,137 @@
+use std::time::Duration;
+
+use tokio::net::{TcpStream, ToSocketAddrs};
+use uuid7::Uuid;
+
+use super::{read_frame, write_frame, Request, Response, Seek};
+use crate::{consts::SmallBytes, formats::Log, ErrorType, Position, Result};
+
+/// Client of a [`Server`](super::Server).
+#[derive(Debug)]
+pub struct Client {
+    stream: TcpStream,
+}
+
+impl Client {
+    pub async fn connect(addr: impl ToSocketAddrs + Send) -> Result<Self> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+
+        Ok(Self { stream })
+    }
+
+    async fn request(&mut self, req: Request) -> Result<Response> {
+        write_frame(&mut self.stream, &req).await?;
+
+        match read_frame(&mut self.stream).await? {
+            Some(Response::Error(e)) => Err(ErrorType::Server(e)),
+            Some(resp) => Ok(resp),
+            None => Err(ErrorType::Protocol("Connection closed".to_owned())),
+        }
+    }
+
+    /// Write a log with `body` and returns its UUID. It's acknowledged once
+    /// queued, see the [module docs](super).
+    pub async fn produce(&mut self, topic: &str, body: impl Into<SmallBytes> + Send) -> Result<Uuid> {
+        let uuids = self.produce_batch(topic, [body]).await?;
+        uuids
+            .first()
+            .copied()
+            .ok_or_else(|| ErrorType::Protocol("No UUID returned".to_owned()))
+    }
+
+    /// Write logs with `bodies` and returns their UUIDs in order. They're
+    /// acknowledged once queued, see the [module docs](super).
+    pub async fn produce_batch<B: Into<SmallBytes>>(
+        &mut self,
+        topic: &str,
+        bodies: impl IntoIterator<Item = B> + Send,
+    ) -> Result<Vec<Uuid>> {
+        let mut bodies = bodies.into_iter().map(Into::into).collect::<Vec<_>>();
+        let topic = topic.to_owned();
+
+        let req = if bodies.len() == 1 {
+            Request::Produce {
+                topic,
+                body: bodies.remove(0),
+            }
+        } else {
+            Request::ProduceBatch { topic, bodies }
+        };
+
+        match self.request(req).await? {
+            Response::Produced(uuids) => Ok(uuids),
+            resp => Err(unexpected(&resp)),
+        }
+    }
+
+    /// Read up to `max_records` logs starting from the first one whose UUID
+    /// is not less than `from`, waiting up to `timeout` if there's none
+    /// available. Returns the logs and the UUID to continue from, which is
+    /// `from` if there's none.
+    pub async fn consume(
+        &mut self,
+        topic: &str,
+        from: Uuid,
+        max_records: u32,
+        timeout: Duration,
+    ) -> Result<(Vec<Log>, Uuid)> {
+        match self
+            .consume_from(topic, Seek::Uuid(from), max_records, timeout)
+            .await?
+        {
+            (logs, Seek::Uuid(next)) => Ok((logs, next)),
+            (_, next) => Err(ErrorType::Protocol(format!("Unexpected position {next:?}"))),
+        }
+    }
+
+    /// Same as [`consume`](Client::consume), from `from` returned by the
+    /// previous call, or [`Position::default`] to start from the oldest log.
+    pub async fn consume_at(
+        &mut self,
+        topic: &str,
+        from: Position,
+        max_records: u32,
+        timeout: Duration,
+    ) -> Result<(Vec<Log>, Position)> {
+        match self
+            .consume_from(topic, Seek::Offset(from), max_records, timeout)
+            .await?
+        {
+            (logs, Seek::Offset(next)) => Ok((logs, next)),
+            (_, next) => Err(ErrorType::Protocol(format!("Unexpected position {next:?}"))),
+        }
+    }
+
+    async fn consume_from(
+        &mut self,
+        topic: &str,
+        from: Seek,
+        max_records: u32,
+        timeout: Duration,
+    ) -> Result<(Vec<Log>, Seek)> {
+        let req = Request::Consume {
+            topic: topic.to_owned(),
+            from,
+            max_records,
+            timeout_ms: timeout.as_millis() as _,
+        };
+
+        match self.request(req).await? {
+            Response::Records { logs, next } => Ok((logs, next)),
+            resp => Err(unexpected(&resp)),
+        }
+    }
+
+    /// Returns names of all topics served, sorted.
+    pub async fn list_topics(&mut self) -> Result<Vec<String>> {
+        match self.request(Request::ListTopics).await? {
+            Response::Topics(topics) => Ok(topics),
+            resp => Err(unexpected(&resp)),
+        }
+    }
+}
+
+fn unexpected(resp: &Response) -> ErrorType {
+    ErrorType::Protocol(format!("Unexpected response {resp:?}"))
+}
@@ -0,0 +1,246 @@
+//! A small framed TCP protocol to produce and consume logs over the network,
+//! enabled with the `server` feature.
+//!
+//! Every frame is a `u32` little endian length followed by a bincode encoded
+//! [`Request`] or [`Response`]. Requests on one connection are handled in
+//! order.
+//!
+//! Produced logs are acknowledged once they're queued to the topic, or once
+//! their order is checked if the topic has an
+//! [`OrderingPolicy`](crate::OrderingPolicy), so they may be lost if it fails
+//! before writing them. Consumers resume from the UUID
+//! returned with each batch, which assumes UUIDs increase within the topic,
+//! see [`OrderingPolicy`](crate::OrderingPolicy), or from the
+//! [`Position`](crate::Position) returned if they consume by offset.
+//!
+//! ```ignore
+//! let server = Server::new();
+//! server.add_topic(topic);
+//!
+//! let listener = TcpListener::bind("127.0.0.1:8080").await?;
+//! tokio::spawn(async move { server.serve(listener).await });
+//!
+//! let mut client = Client::connect("127.0.0.1:8080").await?;
+//! let uuid = client.produce("test", "hello").await?;
+//! ```
+
+mod_use::mod_use![client, protocol];
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use bincode::Options;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpListener,
+    task::spawn_blocking,
+};
+use tracing::{debug, trace};
+use uuid7::Uuid;
+
+use crate::{
+    formats::Log,
+    inner::{joined, Origin},
+    ErrorType, Result, Topic,
+};
+
+/// Serves [`Topic`]s over TCP.
+#[derive(Debug, Clone, Default)]
+pub struct Server {
+    topics: Arc<RwLock<HashMap<String, Arc<Topic>>>>,
+}
+
+impl Server {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serve `topic` under its name. Replaces the topic with the same name, if
+    /// any.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn add_topic(&self, topic: Topic) -> Option<Arc<Topic>> {
+        let name = topic.config().topic().to_owned();
+        self.topics.write().unwrap().insert(name, Arc::new(topic))
+    }
+
+    /// Stop serving the topic with `name`.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn remove_topic(&self, name: &str) -> Option<Arc<Topic>> {
+        self.topics.write().unwrap().remove(name)
+    }
+
+    fn topic(&self, name: &str) -> Result<Arc<Topic>> {
+        self.topics
+            .read()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| ErrorType::Server(format!("Unknown topic {name}")))
+    }
+
+    /// Accept connections from `listener` and handle each of them in a new
+    /// task.
+    pub async fn serve(&self, listener: TcpListener) -> Result<()> {
+        loop {
+            let (stream, addr) = listener.accept().await?;
+            let this = self.clone();
+
+            trace!(%addr, "Accepted connection");
+
+            tokio::spawn(async move {
+                if let Err(e) = this.handle(stream).await {
+                    debug!(%addr, error = %e, "Connection closed with error");
+                }
+            });
+        }
+    }
+
+    /// Handle requests from one connection until it's closed.
+    pub async fn handle<S: AsyncRead + AsyncWrite + Unpin + Send>(
+        &self,
+        mut stream: S,
+    ) -> Result<()> {
+        while let Some(req) = read_frame::<_, Request>(&mut stream).await? {
+            let resp = self
+                .respond(req)
+                .await
+                .unwrap_or_else(|e| Response::Error(e.to_string()));
+
+            write_frame(&mut stream, &resp).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn respond(&self, req: Request) -> Result<Response> {
+        match req {
+            Request::Produce { topic, body } => {
+                let writer = self.topic(&topic)?.writer();
+                let uuid = writer.send(Log::new(body), Origin::Generated).await?;
+                Ok(Response::Produced(vec![uuid]))
+            }
+            Request::ProduceBatch { topic, bodies } => {
+                let writer = self.topic(&topic)?.writer();
+                let mut uuids = Vec::with_capacity(bodies.len());
+                for body in bodies {
+                    uuids.push(writer.send(Log::new(body), Origin::Generated).await?);
+                }
+                Ok(Response::Produced(uuids))
+            }
+            Request::Consume {
+                topic,
+                from,
+                max_records,
+                timeout_ms,
+            } => {
+                let topic = self.topic(&topic)?;
+                let timeout = Duration::from_millis(timeout_ms);
+                consume(topic, from, max_records as _, timeout).await
+            }
+            Request::ListTopics => {
+                let mut topics = self
+                    .topics
+                    .read()
+                    .unwrap()
+                    .keys()
+                    .cloned()
+                    .collect::<Vec<_>>();
+                topics.sort_unstable();
+                Ok(Response::Topics(topics))
+            }
+        }
+    }
+}
+
+/// Bytes of a [`Response::Records`] besides its logs, rounded up
+const RECORDS_OVERHEAD: usize = 1 << 10;
+
+/// Read up to `max` logs starting from `from`, and what fits in a frame. Waits
+/// up to `timeout` for the first one, then only takes what's immediately
+/// available, see [`Reader::next_batch`](crate::Reader::next_batch).
+async fn consume(topic: Arc<Topic>, from: Seek, max: usize, timeout: Duration) -> Result<Response> {
+    // Archived segments may be downloaded
+    let start = from.clone();
+    let mut reader = joined(
+        spawn_blocking(move || match &start {
+            Seek::Offset(position) if position.segment.is_empty() => topic.reader_from(Uuid::NIL),
+            Seek::Offset(position) => topic.reader_at_position(position),
+            Seek::Uuid(uuid) => topic.reader_from(*uuid),
+        })
+        .await,
+    )?;
+
+    let budget = MAX_FRAME_SIZE as usize - RECORDS_OVERHEAD;
+    let logs = reader.next_batch(max, budget, timeout).await?;
+    // The first log is returned regardless of its size
+    if let Some(log) = logs.first().filter(|log| log.byte_len() > budget) {
+        return Err(ErrorType::Server(format!(
+            "Log {} is too large to be sent",
+            log.uuid
+        )));
+    }
+
+    // Logs left in the reader are read again from there
+    let next = match from {
+        Seek::Uuid(uuid) => Seek::Uuid(logs.last().map_or(uuid, |log| uuid_next(log.uuid))),
+        Seek::Offset(_) => Seek::Offset(reader.position()),
+    };
+
+    Ok(Response::Records { logs, next })
+}
+
+/// Returns the UUID right after `uuid`, so consuming from it skips `uuid`.
+fn uuid_next(uuid: Uuid) -> Uuid {
+    Uuid::from(
+        u128::from_be_bytes(*uuid.as_bytes())
+            .saturating_add(1)
+            .to_be_bytes(),
+    )
+}
+
+fn frame_option() -> impl Options + Copy {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_little_endian()
+        .with_limit(u64::from(MAX_FRAME_SIZE))
+}
+
+/// Read a frame, returns `None` if the stream is closed between frames.
+async fn read_frame<R, T>(r: &mut R) -> Result<Option<T>>
+where
+    R: AsyncRead + Unpin + Send,
+    T: DeserializeOwned,
+{
+    let len = match r.read_u32_le().await {
+        Ok(len) => len,
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    if len > MAX_FRAME_SIZE {
+        return Err(ErrorType::Protocol(format!("Frame too large: {len}")));
+    }
+
+    let mut buf = vec![0; len as usize];
+    r.read_exact(&mut buf).await?;
+
+    Ok(Some(frame_option().deserialize(&buf)?))
+}
+
+async fn write_frame<W, T>(w: &mut W, value: &T) -> Result<()>
+where
+    W: AsyncWrite + Unpin + Send,
+    T: Serialize + Sync,
+{
+    let buf = frame_option().serialize(value)?;
+
+    w.write_u32_le(buf.len() as _).await?;
+    w.write_all(&buf).await?;
+    w.flush().await?;
+
+    Ok(())
+}
@@ -0,0 +1,52 @@
+use serde::{Deserialize, Serialize};
+use uuid7::Uuid;
+
+use crate::{consts::SmallBytes, formats::Log, Position};
+
+/// Maximum size of one frame, 16MB.
+pub const MAX_FRAME_SIZE: u32 = 1 << 24;
+
+/// Where to start consuming.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Seek {
+    /// The first log whose UUID is not less than this, in any segment.
+    Uuid(Uuid),
+    /// A position returned in [`Response::Records`], see [`Position`]. The
+    /// default one starts from the oldest log.
+    Offset(Position),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    Produce {
+        topic: String,
+        body: SmallBytes,
+    },
+    ProduceBatch {
+        topic: String,
+        bodies: Vec<SmallBytes>,
+    },
+    /// Long-poll logs starting from `from`, waiting up to `timeout_ms` if
+    /// there's none available. Logs are returned up to `max_records` and what
+    /// fits in a frame.
+    Consume {
+        topic: String,
+        from: Seek,
+        max_records: u32,
+        timeout_ms: u64,
+    },
+    ListTopics,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    /// UUIDs the produced logs are written with, in order. Sent once they're
+    /// queued, see [`Topic::write_one`](crate::Topic::write_one).
+    Produced(Vec<Uuid>),
+    /// Consumed logs, without their headers, and where to continue from, of
+    /// the same kind as the request: the UUID right after the last log
+    /// returned, or the position of the next one.
+    Records { logs: Vec<Log>, next: Seek },
+    Topics(Vec<String>),
+    Error(String),
+}
@@ -0,0 +1,118 @@
+//! [`Sink`] interface of [`Writer`], to write logs from futures-based
+//! pipelines.
+//!
+//! ```ignore
+//! use futures::StreamExt;
+//!
+//! // Copy logs of one topic to another, keeping their UUIDs
+//! source.reader().forward(target.writer().sink()).await?;
+//! ```
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures_core::{ready, Future};
+use futures_sink::Sink;
+
+use crate::{consts::SmallBytes, formats::Log, inner::Origin, ErrorType, Result, Writer};
+
+type SendFuture = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+/// A [`Writer`] as a [`Sink`], obtained by [`Writer::sink`].
+///
+/// [`Log`]s are written with [`Writer::write_one`], and bodies are written
+/// with [`Writer::write`]. Only one log is sent at a time, so the sink is
+/// not ready until the channel of the topic has room for the previous one.
+/// Errors of a log are returned when the sink is polled next.
+pub struct WriterSink {
+    writer: Writer,
+    sending: Option<SendFuture>,
+}
+
+impl WriterSink {
+    pub(crate) const fn new(writer: Writer) -> Self {
+        Self {
+            writer,
+            sending: None,
+        }
+    }
+
+    #[allow(clippy::missing_const_for_fn)] // Can't drop `self` in const fn
+    pub fn into_inner(self) -> Writer {
+        self.writer
+    }
+
+    fn poll_sent(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let Some(sending) = &mut self.sending else { return Poll::Ready(Ok(())) };
+
+        let res = ready!(sending.as_mut().poll(cx));
+        self.sending = None;
+
+        Poll::Ready(res)
+    }
+
+    fn start(&mut self, log: Log, origin: Origin) {
+        debug_assert!(self.sending.is_none(), "`poll_ready` must be called first");
+
+        let writer = self.writer.clone();
+        self.sending = Some(Box::pin(async move {
+            writer.send(log, origin).await?;
+            Ok(())
+        }));
+    }
+}
+
+impl std::fmt::Debug for WriterSink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WriterSink")
+            .field("writer", &self.writer)
+            .field("sending", &self.sending.is_some())
+            .finish()
+    }
+}
+
+impl Sink<Log> for WriterSink {
+    type Error = ErrorType;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.get_mut().poll_sent(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, log: Log) -> Result<()> {
+        self.get_mut().start(log, Origin::Given);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.get_mut().poll_sent(cx)
+    }
+
+    /// Same as [`poll_flush`](Sink::poll_flush), the topic is not closed
+    /// since it may have other writers.
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.get_mut().poll_sent(cx)
+    }
+}
+
+impl Sink<SmallBytes> for WriterSink {
+    type Error = ErrorType;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.get_mut().poll_sent(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, body: SmallBytes) -> Result<()> {
+        self.get_mut().start(Log::new(body), Origin::Generated);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.get_mut().poll_sent(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.get_mut().poll_sent(cx)
+    }
+}
@@ -0,0 +1,333 @@
+//! Truncation of the head and the tail of a topic, see
+//! [`Topic::truncate_before`] and [`Topic::truncate_after`].
+//!
+//! Truncation is applied by the background task once logs queued before it
+//! are written and the segment being written is rolled, so only finished
+//! segments are changed.
+//!
+//! - Dropping the tail is planned first, and the plan is persisted before any
+//!   file is changed. Each step of the plan can be applied again, so a plan
+//!   left by a crash is finished when the topic is opened next.
+//! - Dropping the head persists the new start of the topic first, so logs
+//!   before it are hidden from then on. Segments that only have such logs are
+//!   removed after, or when the topic is opened next if that was interrupted.
+//!
+//! A segment cut in the middle is not shrunk right away, since readers may
+//! still map it. Its committed length is lowered and it's marked unclean, so
+//! it's truncated by [`repair`](crate::repair) when the topic is opened next.
+//!
+//! [`Topic::truncate_before`]: crate::Topic::truncate_before
+//! [`Topic::truncate_after`]: crate::Topic::truncate_after
+
+use std::{
+    fs::{self, File},
+    io::{ErrorKind, Read, Seek, SeekFrom, Write},
+    path::Path,
+    sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
+use tap::{Pipe, Tap};
+use tokio::sync::oneshot;
+use uuid7::Uuid;
+
+use crate::{
+    archive::{copy_atomic, ArchiveBackend, SegmentFile},
+    consts::{HEADER_SIZE, INDEX_SIZE},
+    formats::{Attributes, Header, RecordFormat, UuidIndex, BATCH_HEADER_SIZE},
+    inner::{list_segments, SharedMap},
+    ErrorType, Result,
+};
+
+/// File of the start set by [`Truncate::Before`], in the topic directory
+const START_FILE: &str = "start";
+
+/// File of the [`Plan`] being applied, in the topic directory
+const PLAN_FILE: &str = "truncate.pending";
+
+/// Which logs to drop
+#[derive(Debug, Clone, Copy)]
+pub enum Truncate {
+    /// Logs with UUIDs less than this one
+    Before(Uuid),
+    /// Logs with UUIDs greater than this one
+    After(Uuid),
+}
+
+/// A truncation requested from the background task, answered once it's
+/// applied.
+#[derive(Debug)]
+pub struct Truncation {
+    pub truncate: Truncate,
+    pub done: oneshot::Sender<Result<()>>,
+}
+
+/// Changes to segment files made by a truncation.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Plan {
+    /// Segments removed entirely
+    pub remove: Vec<String>,
+    /// The segment cut in the middle, if any
+    pub cut: Option<Cut>,
+}
+
+/// A segment whose logs after `end` are dropped.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Cut {
+    pub segment: String,
+    /// Committed length of the segment after the cut
+    pub end: u64,
+    /// Offset of the batch cut in the middle and the number of records it
+    /// keeps, only in [`FormatVersion::V2`]
+    ///
+    /// [`FormatVersion::V2`]: crate::formats::FormatVersion::V2
+    pub batch: Option<(u64, u32)>,
+}
+
+impl Plan {
+    /// Plan to drop logs after `uuid` from `maps`, which are finished segments
+    /// from the newest one. Returns the plan with the UUID of the last log
+    /// kept, if there's one.
+    pub fn after(
+        maps: impl Iterator<Item = Result<Arc<SharedMap>>>,
+        uuid: Uuid,
+    ) -> Result<(Self, Option<Uuid>)> {
+        let mut plan = Self::default();
+
+        for map in maps {
+            let map = map?;
+            let mut read_at = map.index().map_or(0, |index| index.lower_bound(uuid));
+            let mut last = None;
+
+            while let Some((records, next)) = map.decode(read_at)? {
+                let Some(kept) = records.iter().position(|(_, log)| log.uuid > uuid) else {
+                    last = records.back().map(|(_, log)| log.uuid);
+                    read_at = next;
+                    continue;
+                };
+
+                if kept > 0 {
+                    last = Some(records[kept - 1].1.uuid);
+                }
+                plan.cut = Some(Cut {
+                    segment: map.name().to_owned(),
+                    end: records[kept].0 as _,
+                    batch: (kept > 0).then_some((read_at as _, kept as _)),
+                });
+                break;
+            }
+
+            // All logs of the segment are after `uuid`, or it's empty
+            if last.is_none() {
+                plan.cut = None;
+                plan.remove.push(map.name().to_owned());
+                continue;
+            }
+
+            return Ok((plan, last));
+        }
+
+        Ok((plan, None))
+    }
+
+    /// Plan to drop logs before `uuid` from `maps`, which are finished
+    /// segments from the oldest one. Only segments without logs at or after
+    /// `uuid` are removed, logs before it in the next segment are hidden by
+    /// the start of the topic instead.
+    pub fn before(maps: impl Iterator<Item = Result<Arc<SharedMap>>>, uuid: Uuid) -> Result<Self> {
+        let mut plan = Self::default();
+
+        for map in maps {
+            let map = map?;
+            if map.last_uuid()?.map_or(false, |last| last >= uuid) {
+                break;
+            }
+            plan.remove.push(map.name().to_owned());
+        }
+
+        Ok(plan)
+    }
+
+    /// Persist the plan to topic directory `dir`, before it's applied.
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        let bytes = bincode::serialize(self)?;
+        copy_atomic(&mut bytes.as_slice(), &dir.join(PLAN_FILE))
+    }
+
+    /// Load the plan left in `dir` by an interrupted truncation, if any.
+    fn load(dir: &Path) -> Result<Option<Self>> {
+        match fs::read(dir.join(PLAN_FILE)) {
+            Ok(bytes) => Ok(Some(bincode::deserialize(&bytes)?)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Remove the persisted plan once it's applied.
+    pub fn done(dir: &Path) -> Result<()> {
+        fs::remove_file(dir.join(PLAN_FILE)).map_err(Into::into)
+    }
+
+    /// Change segment files in `dir` and in `archive` if the topic is archived,
+    /// according to the plan.
+    pub fn apply(&self, dir: &Path, archive: Option<&dyn ArchiveBackend>) -> Result<()> {
+        for name in &self.remove {
+            remove_segment(dir, archive, name)?;
+        }
+
+        if let Some(cut) = &self.cut {
+            cut.apply(dir)?;
+
+            // The archived copy is stale, so it's removed and the segment is archived
+            // again by the next run
+            if let Some(backend) = archive {
+                backend.remove(&cut.segment)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Cut {
+    fn apply(&self, dir: &Path) -> Result<()> {
+        let path = dir.join(&self.segment);
+
+        let mut log = File::options()
+            .read(true)
+            .write(true)
+            .open(path.with_extension(SegmentFile::Log.extension()))?;
+        let header = read_header(&mut log)?;
+
+        if let Some((offset, count)) = self.batch {
+            let at = SeekFrom::Start(HEADER_SIZE as u64 + offset);
+            let mut batch = vec![0; (self.end - offset) as usize];
+            log.seek(at)?;
+            log.read_exact(&mut batch)?;
+
+            let format = RecordFormat::from_attributes(header.attributes());
+            if format.cut_batch(&mut batch, count as _) != Some(batch.len()) {
+                return Err(ErrorType::Bincode(
+                    bincode::ErrorKind::Custom("Corrupted batch".to_owned()).into(),
+                ));
+            }
+
+            log.seek(at)?;
+            log.write_all(&batch[..BATCH_HEADER_SIZE])?;
+            log.sync_data()?;
+        }
+
+        write_committed(&mut log, header, self.end)?;
+
+        let mut idx = match File::options()
+            .read(true)
+            .write(true)
+            .open(path.with_extension(SegmentFile::Index.extension()))
+        {
+            Ok(idx) => idx,
+            // Rebuilt by `repair`
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let header = read_header(&mut idx)?;
+        let mut entries = vec![];
+        idx.read_to_end(&mut entries)?;
+
+        let committed = header
+            .attributes()
+            .watermark()
+            .map_or(entries.len(), |len| (len as usize).min(entries.len()));
+        let kept = entries[..committed]
+            .chunks_exact(INDEX_SIZE)
+            .take_while(|entry| {
+                UuidIndex::from_bytes((*entry).try_into().unwrap()).offset < self.end
+            })
+            .count();
+
+        write_committed(&mut idx, header, (kept * INDEX_SIZE) as _)
+    }
+}
+
+/// Remove segments that only have logs before the start of the topic in `dir`,
+/// which are left by an interrupted [`Truncate::Before`]. Returns the start,
+/// or [`Uuid::NIL`] if the topic was never truncated so.
+pub fn start(dir: &Path, archive: Option<&dyn ArchiveBackend>) -> Result<Uuid> {
+    let start = load_start(dir)?;
+    if start == Uuid::NIL {
+        return Ok(start);
+    }
+
+    let maps = list_segments(dir)?
+        .into_iter()
+        .map(|name| SharedMap::open(dir, &name).map(Arc::new));
+    Plan::before(maps, start)?.apply(dir, archive)?;
+
+    Ok(start)
+}
+
+/// Returns the start of the topic in `dir`, or [`Uuid::NIL`] if it was never
+/// truncated by [`Truncate::Before`].
+pub fn load_start(dir: &Path) -> Result<Uuid> {
+    match fs::read(dir.join(START_FILE)) {
+        Ok(bytes) => <[u8; 16]>::try_from(bytes.as_slice())
+            .map_err(|_| ErrorType::Decode("Invalid start of topic".into()))?
+            .pipe(Uuid::from)
+            .pipe(Ok),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Uuid::NIL),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Persist `uuid` as the start of the topic in `dir`.
+pub fn save_start(dir: &Path, uuid: Uuid) -> Result<()> {
+    copy_atomic(&mut uuid.as_bytes().as_slice(), &dir.join(START_FILE))
+}
+
+/// Finish the plan left in `dir` by an interrupted [`Truncate::After`], if
+/// any. Must be called before segments are repaired, since a batch cut in the
+/// middle may not be valid until then.
+pub fn replay(dir: &Path, archive: Option<&dyn ArchiveBackend>) -> Result<()> {
+    let Some(plan) = Plan::load(dir)? else { return Ok(()) };
+
+    plan.apply(dir, archive)?;
+    Plan::done(dir)
+}
+
+/// Remove both files of segment `name` from `dir` and `archive`. The index is
+/// removed first, so a log left alone gets its index rebuilt.
+fn remove_segment(dir: &Path, archive: Option<&dyn ArchiveBackend>, name: &str) -> Result<()> {
+    let path = dir.join(name);
+    for file in [SegmentFile::Index, SegmentFile::Log] {
+        match fs::remove_file(path.with_extension(file.extension())) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+
+    if let Some(backend) = archive {
+        backend.remove(name)?;
+    }
+
+    Ok(())
+}
+
+fn read_header(file: &mut File) -> Result<Header> {
+    let mut buf = [0; HEADER_SIZE];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut buf)?;
+
+    Ok(Header::from_bytes(&buf))
+}
+
+/// Lower the committed length in `header` of `file` to `len`, and mark the
+/// file unclean so it's truncated when repaired.
+fn write_committed(file: &mut File, mut header: Header, len: u64) -> Result<()> {
+    header.set_attributes(header.attributes().tap_mut(|a| {
+        a.committed = len;
+        a.flags = (a.flags | Attributes::WATERMARK) & !Attributes::CLEAN;
+    }));
+
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&header.as_bytes())?;
+    file.sync_data().map_err(Into::into)
+}
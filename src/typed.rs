@@ -0,0 +1,209 @@
+//! Typed wrappers of [`Topic`], [`Writer`] and [`Reader`] that encode values
+//! into log bodies with a [`Codec`].
+//!
+//! ```ignore
+//! #[derive(Serialize, Deserialize)]
+//! struct Event {
+//!     id: u64,
+//! }
+//!
+//! let topic = TypedTopic::<Event>::new(topic);
+//! topic.writer().write(&Event { id: 1 }).await?;
+//!
+//! let event = topic.reader().next().await.unwrap()?;
+//! ```
+
+use std::{
+    fmt::Debug,
+    marker::PhantomData,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bincode::Options;
+use futures_core::{ready, Future, Stream};
+use serde::{de::DeserializeOwned, Serialize};
+use tap::Pipe;
+use uuid7::Uuid;
+
+use crate::{bincode_option, consts::SmallBytes, ErrorType, Reader, Result, Topic, Writer};
+
+/// Serialization format of log bodies.
+pub trait Codec: Debug + Send + Sync + 'static {
+    /// Errors should be returned as [`ErrorType::Encode`].
+    fn encode<T: Serialize>(value: &T) -> Result<SmallBytes>;
+
+    /// Errors should be returned as [`ErrorType::Decode`].
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T>;
+}
+
+/// [`Codec`] with [`bincode_option`], the same options logs are stored with.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bincode;
+
+impl Codec for Bincode {
+    fn encode<T: Serialize>(value: &T) -> Result<SmallBytes> {
+        bincode_option()
+            .serialize(value)
+            .map(SmallBytes::from_vec)
+            .map_err(|e| ErrorType::Encode(e.into()))
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+        bincode_option()
+            .deserialize(bytes)
+            .map_err(|e| ErrorType::Decode(e.into()))
+    }
+}
+
+/// [`Codec`] with JSON.
+#[cfg(feature = "json")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl Codec for Json {
+    fn encode<T: Serialize>(value: &T) -> Result<SmallBytes> {
+        serde_json::to_vec(value)
+            .map(SmallBytes::from_vec)
+            .map_err(|e| ErrorType::Encode(e.into()))
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+        serde_json::from_slice(bytes).map_err(|e| ErrorType::Decode(e.into()))
+    }
+}
+
+/// A [`Topic`] of `T`, encoded with `C`.
+#[derive(Debug)]
+pub struct TypedTopic<T, C = Bincode> {
+    topic: Topic,
+    _marker: PhantomData<fn() -> (T, C)>,
+}
+
+impl<T, C> TypedTopic<T, C>
+where
+    T: Serialize + DeserializeOwned,
+    C: Codec,
+{
+    pub const fn new(topic: Topic) -> Self {
+        Self {
+            topic,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the underlying [`Topic`].
+    pub const fn topic(&self) -> &Topic {
+        &self.topic
+    }
+
+    #[allow(clippy::missing_const_for_fn)] // Can't drop `self` in const fn
+    pub fn into_inner(self) -> Topic {
+        self.topic
+    }
+
+    /// Returns the [`TypedWriter`] to write values. See [`Topic::writer`].
+    pub fn writer(&self) -> TypedWriter<T, C> {
+        TypedWriter::new(self.topic.writer())
+    }
+
+    /// See [`Topic::reader`].
+    pub fn reader(&self) -> TypedReader<T, C> {
+        TypedReader::new(self.topic.reader())
+    }
+
+    /// See [`Topic::reader_at`].
+    pub fn reader_at(&self, read_at: usize) -> Result<TypedReader<T, C>> {
+        self.topic.reader_at(read_at).map(TypedReader::new)
+    }
+
+    /// See [`Topic::reader_from`].
+    pub fn reader_from(&self, uuid: Uuid) -> Result<TypedReader<T, C>> {
+        self.topic.reader_from(uuid).map(TypedReader::new)
+    }
+}
+
+/// A [`Writer`] of `T`, encoded with `C`.
+#[derive(Debug)]
+pub struct TypedWriter<T, C = Bincode> {
+    writer: Writer,
+    _marker: PhantomData<fn() -> (T, C)>,
+}
+
+impl<T, C> Clone for TypedWriter<T, C> {
+    fn clone(&self) -> Self {
+        Self {
+            writer: self.writer.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T, C> TypedWriter<T, C>
+where
+    T: Serialize,
+    C: Codec,
+{
+    pub const fn new(writer: Writer) -> Self {
+        Self {
+            writer,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Encode `value` and write it with generated UUID. See
+    /// [`Writer::write`].
+    pub fn write(&self, value: &T) -> impl Future<Output = Result<()>> + '_ {
+        let body = C::encode(value);
+        async move { self.writer.write(body?).await }
+    }
+
+    #[allow(clippy::missing_const_for_fn)] // Can't drop `self` in const fn
+    pub fn into_inner(self) -> Writer {
+        self.writer
+    }
+}
+
+pin_project_lite::pin_project! {
+    /// A [`Reader`] of `T`, decoded with `C`. Values that can't be decoded are
+    /// yielded as [`ErrorType::Decode`], and the reader can continue after them.
+    #[derive(Debug)]
+    pub struct TypedReader<T, C = Bincode> {
+        #[pin]
+        reader: Reader,
+        _marker: PhantomData<fn() -> (T, C)>,
+    }
+}
+
+impl<T, C> TypedReader<T, C>
+where
+    T: DeserializeOwned,
+    C: Codec,
+{
+    pub const fn new(reader: Reader) -> Self {
+        Self {
+            reader,
+            _marker: PhantomData,
+        }
+    }
+
+    #[allow(clippy::missing_const_for_fn)] // Can't drop `self` in const fn
+    pub fn into_inner(self) -> Reader {
+        self.reader
+    }
+}
+
+impl<T, C> Stream for TypedReader<T, C>
+where
+    T: DeserializeOwned,
+    C: Codec,
+{
+    type Item = Result<T>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        ready!(self.project().reader.poll_next(cx))
+            .map(|log| log.and_then(|log| C::decode(&log.body)))
+            .pipe(Poll::Ready)
+    }
+}
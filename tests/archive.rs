@@ -0,0 +1,57 @@
+use std::time::Duration;
+
+use futures::StreamExt;
+use limlog::{
+    archive::{ArchiveBackend, LocalArchive},
+    TopicBuilder,
+};
+use tempfile::TempDir;
+use uuid7::Uuid;
+
+mod_use::mod_use!(common);
+
+fn count_segments(dir: &std::path::Path) -> usize {
+    std::fs::read_dir(dir)
+        .unwrap()
+        .filter(|e| e.as_ref().unwrap().path().extension().unwrap() == "limlog")
+        .count()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_archive() {
+    init();
+
+    let dir = TempDir::new().unwrap();
+    let archive_dir = TempDir::new().unwrap();
+    let archive = LocalArchive::new(archive_dir.path()).unwrap();
+
+    let topic = TopicBuilder::new_with_dir("test", dir.path())
+        .unwrap()
+        .with_log_size(1 << 10)
+        .with_archive(archive.clone())
+        .with_local_retention(1)
+        .build()
+        .await
+        .unwrap();
+
+    let w = topic.writer();
+
+    // Each segment holds 36 logs
+    for i in 0..200u32 {
+        w.write(i.to_le_bytes().as_slice()).await.unwrap();
+    }
+
+    // Wait for 5 finished segments to be archived, keeping only the active segment
+    // and one finished segment locally
+    let topic_dir = topic.config().topic_dir();
+    while archive.list().unwrap().len() < 5 || count_segments(&topic_dir) > 2 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    // Read through archived segments
+    let mut r = topic.reader_from(Uuid::NIL).unwrap();
+    for i in 0..200u32 {
+        let log = r.next().await.unwrap().unwrap();
+        assert_eq!(log.body.as_slice(), i.to_le_bytes());
+    }
+}
@@ -1,6 +1,6 @@
 use std::path::PathBuf;
 
-use limlog::{Result, TopicBuilder};
+use limlog::{ErrorType, Result, TopicBuilder};
 use tap::Pipe;
 use tempfile::TempDir;
 use uuid7::Uuid;
@@ -37,3 +37,28 @@ pub async fn write_several(n: usize) -> Result<(TempDir, PathBuf)> {
 
     Ok((dir, topic.config().topic_dir()))
 }
+
+/// Same as [`write_several`], but wait for the topic to stop so that its files
+/// are closed and can be inspected.
+pub async fn write_several_stopped(n: usize) -> Result<(TempDir, PathBuf)> {
+    let dir = TempDir::new()?;
+
+    let topic = TopicBuilder::new_with_dir("test", dir.path())
+        .unwrap()
+        .build()
+        .await
+        .unwrap();
+
+    let w = topic.writer();
+
+    for _ in 0..n {
+        w.write("hello".as_bytes()).await.unwrap();
+    }
+
+    let topic_dir = topic.config().topic_dir();
+
+    topic.stop();
+    assert!(matches!(topic.join().await, Err(ErrorType::Shutdown)));
+
+    Ok((dir, topic_dir))
+}
@@ -0,0 +1,133 @@
+#![cfg(feature = "encryption")]
+
+use futures::{StreamExt, TryStreamExt};
+use limlog::{crypto::EncryptionKey, formats::FormatVersion, ErrorType, Topic, TopicBuilder};
+use tempfile::TempDir;
+use tokio::io::duplex;
+use uuid7::Uuid;
+
+mod_use::mod_use!(common);
+
+const KEY1: EncryptionKey = EncryptionKey::new(1, [1; 32]);
+const KEY2: EncryptionKey = EncryptionKey::new(2, [2; 32]);
+
+fn read_segments(topic: &Topic) -> Vec<u8> {
+    std::fs::read_dir(topic.config().topic_dir())
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().unwrap() == "limlog")
+        .flat_map(|p| std::fs::read(p).unwrap())
+        .collect()
+}
+
+async fn read_all(topic: &Topic) -> limlog::Result<Vec<Vec<u8>>> {
+    topic
+        .range(Uuid::NIL, Uuid::MAX)?
+        .map_ok(|log| log.body.to_vec())
+        .try_collect()
+        .await
+}
+
+#[tokio::test]
+async fn test_encryption() {
+    init();
+    test_encryption_impl(FormatVersion::V1).await;
+    test_encryption_impl(FormatVersion::V2).await;
+}
+
+async fn test_encryption_impl(version: FormatVersion) {
+    let dir = TempDir::new().unwrap();
+    let build = |keys: &[EncryptionKey]| {
+        keys.iter()
+            .cloned()
+            .fold(
+                TopicBuilder::new_with_dir("test", dir.path())
+                    .unwrap()
+                    .with_log_size(1 << 16)
+                    .with_format_version(version),
+                TopicBuilder::with_encryption_key,
+            )
+            .build()
+    };
+
+    let topic = build(&[KEY1]).await.unwrap();
+    let w = topic.writer();
+    let mut r = topic.reader();
+
+    w.write("secret 1".as_bytes()).await.unwrap();
+    assert_eq!(
+        r.next().await.unwrap().unwrap().body.as_slice(),
+        b"secret 1"
+    );
+    assert!(!read_segments(&topic).windows(6).any(|w| w == b"secret"));
+
+    // The segment is rolled on the next write
+    topic.rotate_key(&KEY2);
+    w.write("secret 2".as_bytes()).await.unwrap();
+    assert_eq!(
+        r.next().await.unwrap().unwrap().body.as_slice(),
+        b"secret 2"
+    );
+    assert_eq!(read_all(&topic).await.unwrap(), [b"secret 1", b"secret 2"]);
+
+    drop((w, r));
+    topic.stop();
+    topic.join().await.unwrap_err();
+
+    // Keys of all segments are needed
+    let topic = build(&[KEY2]).await.unwrap();
+    let err = read_all(&topic).await.unwrap_err();
+    assert!(matches!(err, ErrorType::MissingKey(1)));
+    topic.stop();
+    topic.join().await.unwrap_err();
+
+    let topic = build(&[EncryptionKey::new(1, [0; 32]), KEY2])
+        .await
+        .unwrap();
+    let err = read_all(&topic).await.unwrap_err();
+    assert!(matches!(err, ErrorType::Decrypt(_)));
+    topic.stop();
+    topic.join().await.unwrap_err();
+
+    let topic = build(&[KEY1, KEY2]).await.unwrap();
+    assert_eq!(read_all(&topic).await.unwrap(), [b"secret 1", b"secret 2"]);
+}
+
+#[tokio::test]
+async fn test_encrypted_replication() {
+    init();
+
+    let dir = TempDir::new().unwrap();
+    let leader = TopicBuilder::new_with_dir("leader", dir.path())
+        .unwrap()
+        .with_log_size(1 << 12)
+        .with_encryption_key(KEY1)
+        .build()
+        .await
+        .unwrap();
+    let follower = TopicBuilder::new_with_dir("follower", dir.path())
+        .unwrap()
+        .with_encryption_key(KEY1)
+        .with_encryption_key(KEY2)
+        .build()
+        .await
+        .unwrap();
+
+    let w = leader.writer();
+    let mut r = follower.reader();
+
+    let (a, b) = duplex(1 << 10);
+    let (l, f) = (leader.leader(), follower.follower());
+    tokio::spawn(async move { l.serve(a).await });
+    tokio::spawn(async move { f.replicate(b).await });
+
+    // Spans several segments of the leader
+    for i in 0..200u32 {
+        w.write(i.to_le_bytes().as_slice()).await.unwrap();
+    }
+
+    for i in 0..200u32 {
+        let log = r.next().await.unwrap().unwrap();
+        assert_eq!(log.body.as_slice(), i.to_le_bytes());
+    }
+}
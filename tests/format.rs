@@ -52,21 +52,24 @@ fn test_log_format() {
     assert_eq!(
         Log {
             uuid: to_uuid(1, 0),
-            body: smallvec![10]
+            body: smallvec![10],
+            headers: vec![]
         },
         l1
     );
     assert_eq!(
         Log {
             uuid: to_uuid(2, 0),
-            body: smallvec![11]
+            body: smallvec![11],
+            headers: vec![]
         },
         l2
     );
     assert_eq!(
         Log {
             uuid: to_uuid(3, 0),
-            body: smallvec![12]
+            body: smallvec![12],
+            headers: vec![]
         },
         l3
     );
@@ -92,6 +95,7 @@ fn test_ser() {
     let l1 = Log {
         uuid: Uuid::MAX,
         body: smallvec![1, 1, 1, 1, 1, 1, 1, 1],
+        headers: vec![],
     };
     let opt = bincode_option();
     let len = opt.serialized_size(&l1).unwrap() as usize;
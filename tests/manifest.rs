@@ -0,0 +1,102 @@
+use std::time::{Duration, SystemTime};
+
+use futures::StreamExt;
+use limlog::{
+    manifest::{Manifest, ManifestConfig},
+    ErrorType, TopicBuilder,
+};
+use tempfile::TempDir;
+use tokio::time::timeout;
+use uuid7::Uuid;
+
+mod_use::mod_use!(common);
+
+fn builder(dir: &TempDir) -> TopicBuilder {
+    TopicBuilder::new_with_dir("test", dir.path())
+        .unwrap()
+        .with_log_size(1 << 16)
+        .with_index_size(1 << 12)
+}
+
+#[tokio::test]
+async fn test_manifest() {
+    init();
+
+    let dir = TempDir::new().unwrap();
+    let topic = builder(&dir).build().await.unwrap();
+    let topic_dir = topic.config().topic_dir();
+    topic.stop();
+    topic.join().await.unwrap_err();
+
+    let manifest = Manifest::load(&topic_dir).unwrap().unwrap();
+    assert_eq!(manifest.version, Manifest::VERSION);
+    assert!(manifest.created <= SystemTime::now());
+    assert_eq!(manifest.config, ManifestConfig::from(&builder(&dir)));
+    assert_eq!(manifest.config.log_size, 1 << 16);
+
+    // Sizes can't change
+    let err = builder(&dir)
+        .with_log_size(1 << 17)
+        .build()
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        ErrorType::ConfigMismatch {
+            field: "log_size",
+            stored: 65536,
+            configured: 131_072
+        }
+    ));
+    let err = builder(&dir)
+        .with_index_size(1 << 13)
+        .build()
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        ErrorType::ConfigMismatch {
+            field: "index_size",
+            ..
+        }
+    ));
+
+    // Other settings can, and are persisted
+    let conf = builder(&dir).with_record_headers(true);
+    let topic = conf.clone().build().await.unwrap();
+    topic.stop();
+    topic.join().await.unwrap_err();
+
+    let updated = Manifest::load(&topic_dir).unwrap().unwrap();
+    assert_eq!(updated.created, manifest.created);
+    assert_eq!(updated.config, ManifestConfig::from(&conf));
+    assert!(updated.config.record_headers);
+}
+
+#[tokio::test]
+async fn test_read_only_sizes() {
+    init();
+
+    let dir = TempDir::new().unwrap();
+    let topic = builder(&dir).with_grow_chunk(256).build().await.unwrap();
+
+    // Sizes of the writer are taken from the manifest, so the whole segment is
+    // mapped
+    let ro = TopicBuilder::new_with_dir("test", dir.path())
+        .unwrap()
+        .with_log_size(16)
+        .with_index_size(16)
+        .with_poll_interval(Duration::from_millis(10))
+        .build_read_only()
+        .unwrap();
+    let mut r = ro.reader_from(Uuid::NIL).unwrap();
+
+    let w = topic.writer();
+    for i in 0..50u32 {
+        w.write(i.to_le_bytes().as_slice()).await.unwrap();
+    }
+    for i in 0..50u32 {
+        let log = timeout(Duration::from_secs(5), r.next()).await.unwrap();
+        assert_eq!(log.unwrap().unwrap().body.as_slice(), i.to_le_bytes());
+    }
+}
@@ -0,0 +1,127 @@
+use futures::{StreamExt, TryStreamExt};
+use limlog::{
+    formats::{FormatVersion, Log},
+    IndexPolicy, TopicBuilder,
+};
+use tempfile::TempDir;
+use uuid7::{uuid7, Uuid};
+
+mod_use::mod_use!(common);
+
+#[tokio::test]
+async fn test_range() {
+    init();
+    test_range_impl(IndexPolicy::Dense, FormatVersion::V1).await;
+}
+
+#[tokio::test]
+async fn test_range_sparse() {
+    init();
+    test_range_impl(IndexPolicy::Records(10), FormatVersion::V1).await;
+    test_range_impl(IndexPolicy::Bytes(100), FormatVersion::V1).await;
+}
+
+#[tokio::test]
+async fn test_range_batched() {
+    init();
+    test_range_impl(IndexPolicy::Dense, FormatVersion::V2).await;
+    test_range_impl(IndexPolicy::Bytes(100), FormatVersion::V2).await;
+}
+
+async fn test_range_impl(policy: IndexPolicy, version: FormatVersion) {
+    let dir = TempDir::new().unwrap();
+    let topic = TopicBuilder::new_with_dir("test", dir.path())
+        .unwrap()
+        .with_log_size(1 << 10)
+        .with_index_policy(policy)
+        .with_format_version(version)
+        .build()
+        .await
+        .unwrap();
+
+    // Spans several segments, each holds 35 logs in v1
+    let mut uuids = vec![];
+    for i in 0..100u32 {
+        let log = Log::new(i.to_le_bytes().as_slice());
+        uuids.push(log.uuid);
+        topic.write_one(log).await.unwrap();
+    }
+
+    // Wait for all logs to be written
+    let mut r = topic.reader_from(Uuid::NIL).unwrap();
+    for uuid in &uuids {
+        assert_eq!(r.next().await.unwrap().unwrap().uuid, *uuid);
+    }
+
+    let range = |start, end| {
+        let range = topic.range(start, end).unwrap();
+        async move {
+            range
+                .map_ok(|log| log.uuid)
+                .try_collect::<Vec<_>>()
+                .await
+                .unwrap()
+        }
+    };
+
+    assert_eq!(range(uuids[10], uuids[79]).await, uuids[10..=79]);
+    assert_eq!(range(uuids[40], uuids[40]).await, uuids[40..=40]);
+    assert_eq!(range(Uuid::NIL, Uuid::MAX).await, uuids);
+    assert_eq!(range(uuids[99], Uuid::MAX).await, uuids[99..]);
+    assert!(range(Uuid::NIL, Uuid::NIL).await.is_empty());
+    assert!(range(uuids[50], uuids[49]).await.is_empty());
+
+    // Start from the middle of a segment
+    let mut r = topic.reader_from(uuids[50]).unwrap();
+    assert_eq!(r.next().await.unwrap().unwrap().uuid, uuids[50]);
+
+    // Both finished and active segments
+    for (i, uuid) in uuids.iter().enumerate() {
+        let log = topic.get(*uuid).unwrap().unwrap();
+        assert_eq!(log.body.as_slice(), (i as u32).to_le_bytes());
+    }
+    let logs = topic
+        .get_many(&[uuids[99], Uuid::NIL, uuid7(), uuids[0]])
+        .unwrap();
+    assert_eq!(
+        logs.into_iter()
+            .map(|log| log.map(|log| log.uuid))
+            .collect::<Vec<_>>(),
+        [Some(uuids[99]), None, None, Some(uuids[0])]
+    );
+}
+
+#[tokio::test]
+async fn test_sparse_index_size() {
+    init();
+
+    let dir = TempDir::new().unwrap();
+    let topic = TopicBuilder::new_with_dir("test", dir.path())
+        .unwrap()
+        .with_index_policy(IndexPolicy::Records(10))
+        .build()
+        .await
+        .unwrap();
+
+    let w = topic.writer();
+    let mut r = topic.reader();
+    for _ in 0..100 {
+        w.write("hello".as_bytes()).await.unwrap();
+    }
+    for _ in 0..100 {
+        r.next().await.unwrap().unwrap();
+    }
+
+    // Files are truncated when all references to the segment are dropped
+    drop((w, r));
+    let topic_dir = topic.config().topic_dir();
+    topic.stop();
+    topic.join().await.unwrap_err();
+
+    let idx = std::fs::read_dir(topic_dir)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .find(|p| p.extension().unwrap() == "idx")
+        .unwrap();
+    assert_eq!(std::fs::metadata(idx).unwrap().len(), 16 + 10 * 24);
+}
@@ -0,0 +1,148 @@
+use std::time::Duration;
+
+use futures::{StreamExt, TryStreamExt};
+use limlog::{
+    consts::HEADER_SIZE,
+    formats::{Attributes, Header, Log},
+    ErrorType, Topic, TopicBuilder,
+};
+use tempfile::TempDir;
+use tokio::time::{sleep, timeout};
+use uuid7::Uuid;
+
+mod_use::mod_use!(common);
+
+const POLL: Duration = Duration::from_millis(10);
+
+fn builder(dir: &TempDir) -> TopicBuilder {
+    TopicBuilder::new_with_dir("test", dir.path())
+        .unwrap()
+        .with_log_size(1 << 16)
+        .with_index_size(1 << 12)
+        .with_poll_interval(POLL)
+}
+
+async fn uuids(topic: &Topic) -> Vec<Uuid> {
+    topic
+        .range(Uuid::NIL, Uuid::MAX)
+        .unwrap()
+        .map_ok(|log| log.uuid)
+        .try_collect()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_read_only() {
+    init();
+
+    let dir = TempDir::new().unwrap();
+    let topic = builder(&dir)
+        .with_max_records(10)
+        .with_grow_chunk(256)
+        .build()
+        .await
+        .unwrap();
+
+    let logs = (0..30u32)
+        .map(|i| Log::new(i.to_le_bytes().as_slice()))
+        .collect::<Vec<_>>();
+    let all = logs.iter().map(|log| log.uuid).collect::<Vec<_>>();
+    for log in &logs[..5] {
+        topic.write_one(log.clone()).await.unwrap();
+    }
+
+    let ro = builder(&dir).build_read_only().unwrap();
+    let mut r = ro.reader_from(Uuid::NIL).unwrap();
+
+    // Logs written after it's opened are found by polling, through rolls and grown
+    // files
+    for log in &logs[5..] {
+        topic.write_one(log.clone()).await.unwrap();
+    }
+    for log in &logs {
+        let read = timeout(Duration::from_secs(5), r.next()).await.unwrap();
+        assert_eq!(&read.unwrap().unwrap(), log);
+    }
+
+    assert_eq!(uuids(&ro).await, all);
+    assert_eq!(ro.get(all[17]).unwrap().unwrap(), logs[17]);
+    assert!(matches!(
+        ro.writer().write("nope".as_bytes()).await,
+        Err(ErrorType::ReadOnly)
+    ));
+    assert!(matches!(
+        ro.truncate_after(Uuid::NIL).await,
+        Err(ErrorType::ReadOnly)
+    ));
+
+    // Truncations by the writer are found by polling too
+    let mut ahead = ro.reader_from(all[20]).unwrap();
+    topic.truncate_after(all[14]).await.unwrap();
+    sleep(POLL * 10).await;
+
+    assert_eq!(uuids(&ro).await, all[..15]);
+    assert!(matches!(
+        ahead.next().await.unwrap(),
+        Err(ErrorType::Truncated)
+    ));
+
+    drop((r, ahead));
+    ro.stop();
+    assert!(matches!(ro.join().await, Err(ErrorType::Shutdown)));
+
+    // Opened with default sizes once the writer is stopped
+    topic.stop();
+    assert!(matches!(topic.join().await, Err(ErrorType::Shutdown)));
+    let ro = Topic::open_read_only(dir.path().join("test")).unwrap();
+    assert_eq!(uuids(&ro).await, all[..15]);
+}
+
+#[tokio::test]
+async fn test_repair_while_tailed() {
+    init();
+
+    let dir = TempDir::new().unwrap();
+    let topic = builder(&dir).build().await.unwrap();
+    let logs = (0..10u32)
+        .map(|i| Log::new(i.to_le_bytes().as_slice()))
+        .collect::<Vec<_>>();
+    for log in &logs {
+        topic.write_one(log.clone()).await.unwrap();
+    }
+
+    // The tailer maps the segment while it's written
+    let ro = builder(&dir).build_read_only().unwrap();
+    let mut r = ro.reader_from(Uuid::NIL).unwrap();
+    for log in &logs {
+        let read = timeout(Duration::from_secs(5), r.next()).await.unwrap();
+        assert_eq!(&read.unwrap().unwrap(), log);
+    }
+
+    topic.stop();
+    topic.join().await.unwrap_err();
+
+    // As if the writer crashed, the segment is left unclean
+    let path = std::fs::read_dir(dir.path().join("test"))
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .find(|p| p.extension() == Some("limlog".as_ref()))
+        .unwrap();
+    let header = |path| {
+        let bytes = std::fs::read(path).unwrap();
+        Header::from_bytes(bytes[..HEADER_SIZE].try_into().unwrap())
+    };
+    let mut unclean = header(&path);
+    let mut attr = unclean.attributes();
+    attr.flags &= !Attributes::CLEAN;
+    unclean.set_attributes(attr);
+    let mut bytes = std::fs::read(&path).unwrap();
+    bytes[..HEADER_SIZE].copy_from_slice(&unclean.as_bytes());
+    std::fs::write(&path, bytes).unwrap();
+
+    // The tailer doesn't hold off the repair
+    let topic = builder(&dir).build().await.unwrap();
+    assert!(header(&path).attributes().has(Attributes::CLEAN));
+    assert_eq!(uuids(&topic).await.len(), logs.len());
+    drop(r);
+}
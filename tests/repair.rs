@@ -0,0 +1,249 @@
+use std::path::Path;
+
+use bincode::Options;
+use futures::TryStreamExt;
+use limlog::{
+    bincode_option,
+    consts::HEADER_SIZE,
+    formats::{Attributes, Header, Log, UuidIndex},
+    repair::{rebuild_index, repair_segment, RepairReport},
+    TopicBuilder,
+};
+use tempfile::TempDir;
+use uuid7::{uuid7, Uuid};
+
+mod_use::mod_use!(common);
+
+fn header(header: Header, committed: usize) -> [u8; HEADER_SIZE] {
+    let mut header = header;
+    header.set_attributes(Attributes {
+        committed: committed as _,
+        flags: Attributes::WATERMARK,
+        key_id: 0,
+    });
+    header.as_bytes()
+}
+
+/// Write a segment with 3 logs followed by a torn one, as if the writer
+/// crashed, and returns its name.
+fn write_torn(dir: &Path) -> String {
+    let name = uuid7().encode().to_string();
+
+    let (mut log, mut idx) = (vec![], vec![]);
+    for i in 0..4u8 {
+        let record = bincode_option()
+            .serialize(&Log::new([i; 5].as_slice()))
+            .unwrap();
+        let uuid = Uuid::from(<[u8; 16]>::try_from(&record[..16]).unwrap());
+        idx.extend(
+            UuidIndex {
+                uuid,
+                offset: log.len() as _,
+            }
+            .as_bytes(),
+        );
+        log.extend(if i == 3 { &record[..10] } else { &record });
+    }
+
+    write_segment(dir, &name, &log, &idx);
+
+    name
+}
+
+/// Write a segment with `log` and `idx` committed, followed by unwritten
+/// space.
+fn write_segment(dir: &Path, name: &str, log: &[u8], idx: &[u8]) {
+    let log = [header(Header::LOG, log.len()).as_slice(), log, &[0; 100]].concat();
+    let idx = [header(Header::INDEX, idx.len()).as_slice(), idx, &[0; 100]].concat();
+
+    std::fs::write(dir.join(name).with_extension("limlog"), log).unwrap();
+    std::fs::write(dir.join(name).with_extension("idx"), idx).unwrap();
+}
+
+/// Encode `logs` and their index entries.
+fn encode(logs: &[Log]) -> (Vec<u8>, Vec<u8>) {
+    let (mut log, mut idx) = (vec![], vec![]);
+    for record in logs {
+        idx.extend(
+            UuidIndex {
+                uuid: record.uuid,
+                offset: log.len() as _,
+            }
+            .as_bytes(),
+        );
+        log.extend(bincode_option().serialize(record).unwrap());
+    }
+    (log, idx)
+}
+
+#[test]
+fn test_repair_segment() {
+    init();
+
+    let dir = TempDir::new().unwrap();
+    let name = write_torn(dir.path());
+
+    let report = repair_segment(dir.path(), &name).unwrap();
+    assert_eq!(
+        report,
+        RepairReport {
+            segment: name.clone(),
+            valid_len: 3 * 29,
+            discarded_bytes: 10,
+            discarded_indexes: 1,
+            index_rebuilt: false,
+        }
+    );
+
+    let log = std::fs::read(dir.path().join(&name).with_extension("limlog")).unwrap();
+    let idx = std::fs::read(dir.path().join(&name).with_extension("idx")).unwrap();
+    assert_eq!(log.len(), HEADER_SIZE + 3 * 29);
+    assert_eq!(idx.len(), HEADER_SIZE + 3 * 24);
+
+    let attr = Header::from_bytes(log[..HEADER_SIZE].try_into().unwrap()).attributes();
+    assert!(attr.has(Attributes::CLEAN));
+    assert_eq!(attr.committed, 3 * 29);
+
+    // Nothing to repair anymore
+    assert!(repair_segment(dir.path(), &name).unwrap().is_intact());
+}
+
+#[test]
+fn test_repair_zeroed_tail() {
+    init();
+
+    let dir = TempDir::new().unwrap();
+    let name = uuid7().encode().to_string();
+    let (mut log, idx) = encode(
+        &(0..3u8)
+            .map(|i| Log::new([i; 5].as_slice()))
+            .collect::<Vec<_>>(),
+    );
+    // The watermark reached the disk but the last record didn't
+    log.extend([0; 29]);
+    write_segment(dir.path(), &name, &log, &idx);
+
+    let report = repair_segment(dir.path(), &name).unwrap();
+    assert_eq!(report.valid_len, 3 * 29);
+    assert_eq!(report.discarded_bytes, 29);
+    assert_eq!(report.discarded_indexes, 0);
+}
+
+#[test]
+fn test_repair_uuid_backwards() {
+    init();
+
+    let dir = TempDir::new().unwrap();
+    let name = uuid7().encode().to_string();
+    let logs = (0..3u8)
+        .map(|i| Log::new([i; 5].as_slice()))
+        .collect::<Vec<_>>();
+    let (mut log, idx) = encode(&logs);
+    // Not indexed, and older than the last indexed log
+    log.extend(encode(&[logs[0].clone()]).0);
+    write_segment(dir.path(), &name, &log, &idx);
+
+    let report = repair_segment(dir.path(), &name).unwrap();
+    assert_eq!(report.valid_len, 3 * 29);
+    assert_eq!(report.discarded_bytes, 29);
+
+    // Out of order logs confirmed by the index are kept
+    let name = uuid7().encode().to_string();
+    let (log, idx) = encode(&[logs[1].clone(), logs[0].clone(), logs[2].clone()]);
+    write_segment(dir.path(), &name, &log, &idx);
+
+    assert!(repair_segment(dir.path(), &name).unwrap().is_intact());
+}
+
+#[test]
+fn test_repair_index_mismatch() {
+    init();
+
+    let dir = TempDir::new().unwrap();
+    let name = uuid7().encode().to_string();
+    let logs = (0..3u8)
+        .map(|i| Log::new([i; 5].as_slice()))
+        .collect::<Vec<_>>();
+    let (log, mut idx) = encode(&logs);
+    // The index saw another second log, so the log is torn from there
+    idx[24..40].copy_from_slice(uuid7().as_bytes());
+    write_segment(dir.path(), &name, &log, &idx);
+
+    let report = repair_segment(dir.path(), &name).unwrap();
+    assert_eq!(
+        report,
+        RepairReport {
+            segment: name,
+            valid_len: 29,
+            discarded_bytes: 2 * 29,
+            discarded_indexes: 2,
+            index_rebuilt: false,
+        }
+    );
+}
+
+#[tokio::test]
+async fn test_recover() {
+    init();
+
+    let dir = TempDir::new().unwrap();
+    std::fs::create_dir(dir.path().join("test")).unwrap();
+    write_torn(&dir.path().join("test"));
+
+    let topic = TopicBuilder::new_with_dir("test", dir.path())
+        .unwrap()
+        .build()
+        .await
+        .unwrap();
+
+    let logs = topic
+        .range(Uuid::NIL, Uuid::MAX)
+        .unwrap()
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+    assert_eq!(logs.len(), 3);
+    for (i, log) in logs.iter().enumerate() {
+        assert_eq!(log.body.as_slice(), [i as u8; 5]);
+    }
+}
+
+#[tokio::test]
+async fn test_rebuild_index() {
+    init();
+
+    // Logs still queued when stopped are dropped, so there may be less than 100
+    let (tmp, dir) = write_several_stopped(100).await.unwrap();
+    let name = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .find(|p| p.extension().unwrap() == "idx")
+        .unwrap()
+        .file_stem()
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_owned();
+    let path = dir.join(&name).with_extension("idx");
+    let expected = std::fs::read(&path).unwrap();
+
+    std::fs::remove_file(&path).unwrap();
+    let entries = (expected.len() - HEADER_SIZE) / 24;
+    assert_eq!(rebuild_index(&dir, &name).unwrap(), entries as u64);
+    assert_eq!(std::fs::read(&path).unwrap(), expected);
+
+    // Lost the last entries, rebuilt when opening the topic
+    std::fs::File::options()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_len((HEADER_SIZE + entries / 2 * 24) as _)
+        .unwrap();
+
+    let _topic = TopicBuilder::new_with_dir("test", tmp.path())
+        .unwrap()
+        .build()
+        .await
+        .unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), expected);
+}
@@ -0,0 +1,220 @@
+use std::time::Duration;
+
+use futures::StreamExt;
+use limlog::{OrderingPolicy, Topic, TopicBuilder};
+use tempfile::TempDir;
+use tokio::{io::duplex, net::TcpListener};
+use uuid7::Uuid;
+
+mod_use::mod_use!(common);
+
+#[tokio::test]
+async fn test_replication() {
+    init();
+
+    let dir = TempDir::new().unwrap();
+    let leader = TopicBuilder::new_with_dir("leader", dir.path())
+        .unwrap()
+        .with_log_size(1 << 12)
+        .build()
+        .await
+        .unwrap();
+    let follower = TopicBuilder::new_with_dir("follower", dir.path())
+        .unwrap()
+        .build()
+        .await
+        .unwrap();
+
+    let w = leader.writer();
+    let mut r = follower.reader();
+
+    let (a, b) = duplex(1 << 10);
+
+    let l = leader.leader();
+    let f = follower.follower();
+
+    tokio::spawn(async move { l.serve(a).await });
+    tokio::spawn({
+        let f = f.clone();
+        async move { f.replicate(b).await }
+    });
+
+    // Spans several segments of the leader
+    for i in 0..500u32 {
+        w.write(i.to_le_bytes().as_slice()).await.unwrap();
+    }
+
+    for i in 0..500u32 {
+        let log = r.next().await.unwrap().unwrap();
+        assert_eq!(log.body.as_slice(), i.to_le_bytes());
+    }
+
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert_eq!(f.lag().bytes(), 0);
+}
+
+#[tokio::test]
+async fn test_replication_tcp() {
+    init();
+
+    let dir = TempDir::new().unwrap();
+    let leader = TopicBuilder::new_with_dir("leader", dir.path())
+        .unwrap()
+        .build()
+        .await
+        .unwrap();
+    let follower = TopicBuilder::new_with_dir("follower", dir.path())
+        .unwrap()
+        .build()
+        .await
+        .unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let l = leader.leader();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        l.serve(stream).await
+    });
+
+    let f = follower.follower();
+    tokio::spawn(async move {
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        f.replicate(stream).await
+    });
+
+    let mut leader_reader = leader.reader();
+    let mut follower_reader = follower.reader();
+
+    for _ in 0..100 {
+        leader.writer().write("hello".as_bytes()).await.unwrap();
+    }
+
+    for _ in 0..100 {
+        let l = leader_reader.next().await.unwrap().unwrap();
+        let f = follower_reader.next().await.unwrap().unwrap();
+        assert_eq!(l, f);
+    }
+}
+
+#[tokio::test]
+async fn test_replication_resume() {
+    init();
+
+    let dir = TempDir::new().unwrap();
+    let leader = TopicBuilder::new_with_dir("leader", dir.path())
+        .unwrap()
+        .with_log_size(1 << 12)
+        .build()
+        .await
+        .unwrap();
+    let follower = TopicBuilder::new_with_dir("follower", dir.path())
+        .unwrap()
+        .build()
+        .await
+        .unwrap();
+
+    let w = leader.writer();
+    let mut r = follower.reader();
+    let f = follower.follower();
+
+    let connect = || {
+        let (a, b) = duplex(1 << 10);
+        let l = leader.leader();
+        let f = f.clone();
+        (
+            tokio::spawn(async move { l.serve(a).await }),
+            tokio::spawn(async move { f.replicate(b).await }),
+        )
+    };
+
+    // A new follower starts from the oldest log, not the active segment
+    for i in 0..200u32 {
+        w.write(i.to_le_bytes().as_slice()).await.unwrap();
+    }
+    let (serve, replicate) = connect();
+    for i in 0..200u32 {
+        let log = r.next().await.unwrap().unwrap();
+        assert_eq!(log.body.as_slice(), i.to_le_bytes());
+    }
+    serve.abort();
+    replicate.abort();
+
+    // Reconnecting resumes where it stopped, even if the leader rolled meanwhile
+    for i in 200..400u32 {
+        w.write(i.to_le_bytes().as_slice()).await.unwrap();
+    }
+    let (serve, _) = connect();
+    for i in 200..400u32 {
+        let log = r.next().await.unwrap().unwrap();
+        assert_eq!(log.body.as_slice(), i.to_le_bytes());
+    }
+    let more = tokio::time::timeout(Duration::from_millis(50), r.next());
+    assert!(more.await.is_err());
+    serve.abort();
+}
+
+#[tokio::test]
+async fn test_replication_restart() {
+    init();
+
+    let dir = TempDir::new().unwrap();
+    let leader = TopicBuilder::new_with_dir("leader", dir.path())
+        .unwrap()
+        .with_log_size(1 << 12)
+        .build()
+        .await
+        .unwrap();
+    // UUIDs of the leader are kept whatever the policy of the follower
+    let build = || {
+        TopicBuilder::new_with_dir("follower", dir.path())
+            .unwrap()
+            .with_ordering_policy(OrderingPolicy::Restamp)
+            .build()
+    };
+
+    let w = leader.writer();
+    let connect = |follower: &Topic| {
+        let (a, b) = duplex(1 << 10);
+        let l = leader.leader();
+        let f = follower.follower();
+        (
+            tokio::spawn(async move { l.serve(a).await }),
+            tokio::spawn(async move { f.replicate(b).await }),
+        )
+    };
+
+    for i in 0..200u32 {
+        w.write(i.to_le_bytes().as_slice()).await.unwrap();
+    }
+    let follower = build().await.unwrap();
+    let mut r = follower.reader();
+    let (serve, replicate) = connect(&follower);
+    for _ in 0..200u32 {
+        r.next().await.unwrap().unwrap();
+    }
+    serve.abort();
+    replicate.abort();
+    replicate.await.unwrap_err();
+    drop(r);
+    follower.stop();
+    follower.join().await.unwrap_err();
+
+    // The reopened follower resumes after its last log
+    for i in 200..400u32 {
+        w.write(i.to_le_bytes().as_slice()).await.unwrap();
+    }
+    let follower = build().await.unwrap();
+    let _tasks = connect(&follower);
+
+    let mut leader_reader = leader.reader_from(Uuid::NIL).unwrap();
+    let mut r = follower.reader_from(Uuid::NIL).unwrap();
+    for _ in 0..400u32 {
+        let l = leader_reader.next().await.unwrap().unwrap();
+        let f = r.next().await.unwrap().unwrap();
+        assert_eq!(l, f);
+    }
+    let more = tokio::time::timeout(Duration::from_millis(50), r.next());
+    assert!(more.await.is_err());
+}
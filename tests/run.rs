@@ -1,13 +1,24 @@
-use std::pin::pin;
+use std::{pin::pin, time::Duration};
 
-use futures::future::select;
-use limlog::TopicBuilder;
+use futures::{future::select, StreamExt};
+use limlog::{
+    formats::{FormatVersion, Log},
+    DedupWindow, ErrorType, Health, OrderingPolicy, Topic, TopicBuilder,
+};
 use tempfile::TempDir;
 use tokio::signal::ctrl_c;
 use tracing::info;
+use uuid7::Uuid;
 
 mod_use::mod_use!(common);
 
+fn count_segments(topic: &Topic) -> usize {
+    std::fs::read_dir(topic.config().topic_dir())
+        .unwrap()
+        .filter(|e| e.as_ref().unwrap().path().extension().unwrap() == "limlog")
+        .count()
+}
+
 #[tokio::test]
 async fn test_run() {
     init();
@@ -43,3 +54,636 @@ async fn test_run() {
 
     topic.abort();
 }
+
+#[tokio::test]
+async fn test_failure() {
+    init();
+
+    let dir = TempDir::new().unwrap();
+    let topic = TopicBuilder::new_with_dir("test", dir.path())
+        .unwrap()
+        .with_log_size(1 << 10)
+        .build()
+        .await
+        .unwrap();
+
+    let w = topic.writer();
+    let mut r = topic.reader();
+
+    // Rolling will fail since the topic directory is gone
+    std::fs::remove_dir_all(topic.config().topic_dir()).unwrap();
+
+    let err = loop {
+        if let Err(e) = w.write("hello".as_bytes()).await {
+            break e;
+        }
+    };
+
+    let ErrorType::Background(cause) = err else { panic!("Unexpected error: {err}") };
+    assert!(matches!(&*cause, ErrorType::Io(_)));
+    assert!(matches!(topic.health(), Health::Failed(_)));
+
+    // Logs written before the failure are still readable
+    let err = loop {
+        if let Err(e) = r.next().await.unwrap() {
+            break e;
+        }
+    };
+    assert!(matches!(err, ErrorType::Background(_)));
+}
+
+#[tokio::test]
+async fn test_dropped() {
+    init();
+
+    let dir = TempDir::new().unwrap();
+    let topic = TopicBuilder::new_with_dir("test", dir.path())
+        .unwrap()
+        .build()
+        .await
+        .unwrap();
+
+    let w = topic.writer();
+    let r = topic.reader();
+    for _ in 0..3 {
+        w.write("hello".as_bytes()).await.unwrap();
+    }
+
+    // Dropping the topic and all writers stops the background task, which is not a
+    // failure
+    drop((topic, w));
+
+    let logs = tokio::time::timeout(Duration::from_secs(5), r.collect::<Vec<_>>())
+        .await
+        .unwrap();
+    assert_eq!(logs.len(), 3);
+    assert!(logs.iter().all(Result::is_ok));
+}
+
+#[tokio::test]
+async fn test_roll() {
+    init();
+
+    let dir = TempDir::new().unwrap();
+    let topic = TopicBuilder::new_with_dir("test", dir.path())
+        .unwrap()
+        .with_roll_interval(Duration::from_millis(100))
+        .with_max_records(10)
+        .build()
+        .await
+        .unwrap();
+
+    let w = topic.writer();
+    let mut r = topic.reader();
+
+    // Rolled by record count
+    for _ in 0..25 {
+        w.write("hello".as_bytes()).await.unwrap();
+    }
+    for _ in 0..25 {
+        r.next().await.unwrap().unwrap();
+    }
+    assert_eq!(count_segments(&topic), 3);
+
+    // Rolled by time, even if no more log is written
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(count_segments(&topic), 4);
+
+    // The empty segment is not rolled
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(count_segments(&topic), 4);
+
+    w.write("hello".as_bytes()).await.unwrap();
+    r.next().await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_small_logs() {
+    init();
+
+    let dir = TempDir::new().unwrap();
+    let topic = TopicBuilder::new_with_dir("test", dir.path())
+        .unwrap()
+        .with_log_size(1 << 10)
+        .build()
+        .await
+        .unwrap();
+
+    // A 5-byte body takes 29 bytes, readers must not wait for more data to decode
+    // the last one
+    let w = topic.writer();
+    let mut r = topic.reader();
+    w.write("hello".as_bytes()).await.unwrap();
+    let log = tokio::time::timeout(Duration::from_secs(1), r.next())
+        .await
+        .expect("Reader waited for more data")
+        .unwrap()
+        .unwrap();
+    assert_eq!(log.body.as_slice(), b"hello");
+
+    // Same for the last log of a finished segment, which holds 34 of them with
+    // 22 bytes left
+    for _ in 0..40 {
+        w.write("hello".as_bytes()).await.unwrap();
+    }
+    for _ in 0..40 {
+        let log = tokio::time::timeout(Duration::from_secs(1), r.next())
+            .await
+            .expect("Reader waited for more data")
+            .unwrap()
+            .unwrap();
+        assert_eq!(log.body.as_slice(), b"hello");
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_follow_rolls() {
+    init();
+
+    let dir = TempDir::new().unwrap();
+    let topic = TopicBuilder::new_with_dir("test", dir.path())
+        .unwrap()
+        .with_log_size(1 << 12)
+        .with_index_size(1 << 10)
+        .with_max_records(1)
+        .build()
+        .await
+        .unwrap();
+
+    // Readers following the writer never find a finished segment without one after
+    // it, which would end them early
+    let mut r = topic.reader();
+    let reading = tokio::spawn(async move {
+        for i in 0..300u32 {
+            let log = r.next().await.expect("Reader ended early").unwrap();
+            assert_eq!(log.body.as_slice(), i.to_le_bytes());
+        }
+    });
+
+    let w = topic.writer();
+    for i in 0..300u32 {
+        w.write(i.to_le_bytes().as_slice()).await.unwrap();
+    }
+
+    tokio::time::timeout(Duration::from_secs(10), reading)
+        .await
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_grow() {
+    init();
+
+    let dir = TempDir::new().unwrap();
+    let topic = TopicBuilder::new_with_dir("test", dir.path())
+        .unwrap()
+        .with_log_size(1 << 20)
+        .with_grow_chunk(1 << 12)
+        .build()
+        .await
+        .unwrap();
+
+    let file_len = || {
+        let entry = std::fs::read_dir(topic.config().topic_dir())
+            .unwrap()
+            .map(Result::unwrap)
+            .find(|e| e.path().extension().unwrap() == "limlog")
+            .unwrap();
+        entry.metadata().unwrap().len()
+    };
+    assert_eq!(file_len(), (1 << 12) + 16);
+
+    let w = topic.writer();
+    let mut r = topic.reader();
+
+    // Each log takes 29 bytes
+    for i in 0..200u8 {
+        w.write([i; 5].as_slice()).await.unwrap();
+    }
+    for i in 0..200u8 {
+        assert_eq!(r.next().await.unwrap().unwrap().body.as_slice(), [i; 5]);
+    }
+
+    assert_eq!(file_len(), (2 << 12) + 16);
+}
+
+#[tokio::test]
+async fn test_ordering() {
+    init();
+
+    let dir = TempDir::new().unwrap();
+    let build = |policy| {
+        TopicBuilder::new_with_dir("test", dir.path())
+            .unwrap()
+            .with_ordering_policy(policy)
+            .build()
+    };
+    let log = |ts| Log {
+        uuid: to_uuid(ts, 0),
+        body: "hello".as_bytes().into(),
+        headers: vec![],
+    };
+
+    let topic = build(OrderingPolicy::Reject).await.unwrap();
+    let mut r = topic.reader();
+    assert_eq!(topic.write_one(log(2)).await.unwrap(), to_uuid(2, 0));
+    let err = topic.write_one(log(1)).await.unwrap_err();
+    assert!(matches!(err, ErrorType::OutOfOrder { .. }));
+    topic.writer().write("hello".as_bytes()).await.unwrap();
+
+    assert_eq!(r.next().await.unwrap().unwrap().uuid, to_uuid(2, 0));
+    let last = r.next().await.unwrap().unwrap().uuid;
+    assert!(last > to_uuid(2, 0));
+
+    drop(r);
+    topic.stop();
+    topic.join().await.unwrap_err();
+
+    // The last UUID is loaded when reopened
+    let topic = build(OrderingPolicy::Restamp).await.unwrap();
+    let mut r = topic.reader();
+    let first = topic.write_one(log(3)).await.unwrap();
+    let second = topic.write_one(log(4)).await.unwrap();
+    assert!(first > last);
+    assert!(second > first);
+
+    assert_eq!(r.next().await.unwrap().unwrap().uuid, first);
+    assert_eq!(r.next().await.unwrap().unwrap().uuid, second);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_ordering_concurrent() {
+    init();
+
+    let dir = TempDir::new().unwrap();
+    let topic = TopicBuilder::new_with_dir("test", dir.path())
+        .unwrap()
+        .with_ordering_policy(OrderingPolicy::Reject)
+        .build()
+        .await
+        .unwrap();
+    let mut r = topic.reader();
+
+    // UUIDs generated on different threads are not in order, but are not rejected
+    let tasks = (0..8)
+        .map(|_| {
+            let w = topic.writer();
+            tokio::spawn(async move {
+                for _ in 0..500 {
+                    w.write("hello".as_bytes()).await.unwrap();
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for task in tasks {
+        task.await.unwrap();
+    }
+
+    let mut last = Uuid::NIL;
+    for _ in 0..4000 {
+        let uuid = r.next().await.unwrap().unwrap().uuid;
+        assert!(uuid > last);
+        last = uuid;
+    }
+}
+
+#[tokio::test]
+async fn test_dedup() {
+    init();
+
+    let dir = TempDir::new().unwrap();
+    let build = || {
+        TopicBuilder::new_with_dir("test", dir.path())
+            .unwrap()
+            .with_dedup_window(DedupWindow::Count(10))
+            .build()
+    };
+    let log = Log::new("hello".as_bytes());
+
+    // Write `logs` followed by a sentinel, and returns UUIDs read before the
+    // sentinel
+    let write = |topic: Topic, logs: Vec<Log>| async move {
+        let mut r = topic.reader();
+        let sentinel = Log::new("sentinel".as_bytes());
+        for log in logs.into_iter().chain([sentinel.clone()]) {
+            topic.write_one(log).await.unwrap();
+        }
+
+        let mut uuids = vec![];
+        loop {
+            let uuid = r.next().await.unwrap().unwrap().uuid;
+            if uuid == sentinel.uuid {
+                break (topic, uuids);
+            }
+            uuids.push(uuid);
+        }
+    };
+
+    let topic = build().await.unwrap();
+    let (topic, uuids) = write(topic, vec![log.clone(), log.clone()]).await;
+    assert_eq!(uuids, [log.uuid]);
+
+    topic.stop();
+    topic.join().await.unwrap_err();
+
+    // The window is rebuilt when reopened
+    let topic = build().await.unwrap();
+    let (topic, uuids) = write(topic, vec![log.clone()]).await;
+    assert!(uuids.is_empty());
+
+    // Out of the window
+    let logs = (0..10).map(|_| Log::new("hello".as_bytes())).collect();
+    let (topic, _) = write(topic, logs).await;
+    let (_, uuids) = write(topic, vec![log.clone()]).await;
+    assert_eq!(uuids, [log.uuid]);
+}
+
+#[tokio::test]
+async fn test_headers() {
+    init();
+
+    let dir = TempDir::new().unwrap();
+    let build = |enabled| {
+        TopicBuilder::new_with_dir("test", dir.path())
+            .unwrap()
+            .with_record_headers(enabled)
+            .build()
+    };
+    let log = || Log::new("hello".as_bytes()).with_header("trace".as_bytes(), [1, 2, 3].as_slice());
+
+    let topic = build(false).await.unwrap();
+    let err = topic.write_one(log()).await.unwrap_err();
+    assert!(matches!(err, ErrorType::HeadersDisabled));
+    topic.writer().write("hello".as_bytes()).await.unwrap();
+
+    let mut r = topic.reader_from(Uuid::NIL).unwrap();
+    assert!(r.next().await.unwrap().unwrap().headers.is_empty());
+
+    drop(r);
+    topic.stop();
+    topic.join().await.unwrap_err();
+
+    // Segments without headers stay readable after enabling them
+    let topic = build(true).await.unwrap();
+    let written = log();
+    topic.write_one(written.clone()).await.unwrap();
+    topic
+        .writer()
+        .write_with_headers(
+            "world".as_bytes(),
+            vec![("k".as_bytes().into(), "v".as_bytes().into())],
+        )
+        .await
+        .unwrap();
+
+    let mut r = topic.reader_from(Uuid::NIL).unwrap();
+    assert!(r.next().await.unwrap().unwrap().headers.is_empty());
+    assert_eq!(r.next().await.unwrap().unwrap(), written);
+    let log = r.next().await.unwrap().unwrap();
+    assert_eq!(log.header(b"k"), Some(b"v".as_slice()));
+}
+
+#[cfg(feature = "compression")]
+#[tokio::test]
+async fn test_compression() {
+    use limlog::{
+        consts::HEADER_SIZE,
+        formats::{Header, RecordFormat},
+        Compression,
+    };
+
+    init();
+
+    let dir = TempDir::new().unwrap();
+    let build = |compression| {
+        TopicBuilder::new_with_dir("test", dir.path())
+            .unwrap()
+            .with_log_size(1 << 16)
+            .with_compression(compression)
+            .build()
+    };
+    let body = "hello ".repeat(100);
+
+    let topic = build(Compression::None).await.unwrap();
+    topic.writer().write(body.as_bytes()).await.unwrap();
+    let mut r = topic.reader_from(Uuid::NIL).unwrap();
+    assert_eq!(
+        r.next().await.unwrap().unwrap().body.as_slice(),
+        body.as_bytes()
+    );
+
+    drop(r);
+    topic.stop();
+    topic.join().await.unwrap_err();
+
+    // Segments written without compression stay readable
+    let topic = build(Compression::Snappy).await.unwrap();
+    assert_eq!(count_segments(&topic), 2);
+    topic.writer().write(body.as_bytes()).await.unwrap();
+
+    let mut r = topic.reader_from(Uuid::NIL).unwrap();
+    for _ in 0..2 {
+        assert_eq!(
+            r.next().await.unwrap().unwrap().body.as_slice(),
+            body.as_bytes()
+        );
+    }
+    // The watermark is advanced once the logs are synced
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let mut sizes = std::fs::read_dir(topic.config().topic_dir())
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().unwrap() == "limlog")
+        .map(|p| std::fs::read(p).unwrap())
+        .map(|file| Header::from_bytes(file[..HEADER_SIZE].try_into().unwrap()).attributes())
+        .filter(|attr| attr.committed > 0)
+        .map(|attr| {
+            (
+                RecordFormat::from_attributes(attr).compression,
+                attr.committed,
+            )
+        })
+        .collect::<Vec<_>>();
+    sizes.sort_unstable_by_key(|&(_, len)| len);
+    assert_eq!(sizes.len(), 2);
+    assert_eq!(sizes[0].0, Compression::Snappy);
+    assert!(sizes[0].1 < 100, "{sizes:?}");
+    assert_eq!(sizes[1].0, Compression::None);
+}
+
+#[tokio::test]
+async fn test_format_version() {
+    init();
+
+    let dir = TempDir::new().unwrap();
+    let build = |version| {
+        let builder = TopicBuilder::new_with_dir("test", dir.path())
+            .unwrap()
+            .with_log_size(1 << 16)
+            .with_record_headers(true);
+        #[cfg(feature = "compression")]
+        let builder = builder.with_compression(limlog::Compression::Snappy);
+        builder.with_format_version(version).build()
+    };
+    let logs = (0..100u32)
+        .map(|i| {
+            Log::new(i.to_le_bytes().as_slice())
+                .with_header("i".as_bytes(), i.to_le_bytes().as_slice())
+        })
+        .collect::<Vec<_>>();
+
+    let topic = build(FormatVersion::V1).await.unwrap();
+    for log in &logs[..50] {
+        topic.write_one(log.clone()).await.unwrap();
+    }
+    let mut r = topic.reader_from(Uuid::NIL).unwrap();
+    for log in &logs[..50] {
+        assert_eq!(&r.next().await.unwrap().unwrap(), log);
+    }
+    let v1 = topic.reader().cursor();
+
+    drop(r);
+    topic.stop();
+    topic.join().await.unwrap_err();
+
+    // Segments in v1 stay readable
+    let topic = build(FormatVersion::V2).await.unwrap();
+    for log in &logs[50..] {
+        topic.write_one(log.clone()).await.unwrap();
+    }
+    let mut r = topic.reader_from(Uuid::NIL).unwrap();
+    for log in &logs {
+        assert_eq!(&r.next().await.unwrap().unwrap(), log);
+    }
+    // Logs queued together are batched
+    assert!(topic.reader().cursor() < v1 / 2);
+
+    // Seek into the middle of a batch
+    let mut r = topic.reader_from(logs[75].uuid).unwrap();
+    for log in &logs[75..] {
+        assert_eq!(&r.next().await.unwrap().unwrap(), log);
+    }
+}
+
+#[tokio::test]
+async fn test_batch_duplicates() {
+    init();
+
+    let dir = TempDir::new().unwrap();
+    let log = Log::new("hello".as_bytes());
+
+    // Returns the number of `log` stored after writing it 3 times, queued together
+    let count = |version, window: Option<DedupWindow>| {
+        let (log, dir) = (log.clone(), dir.path());
+        let name = format!("{version:?}-{}", window.is_some());
+        async move {
+            let builder = TopicBuilder::new_with_dir(name, dir)
+                .unwrap()
+                .with_format_version(version);
+            let topic = match window {
+                Some(window) => builder.with_dedup_window(window),
+                None => builder,
+            }
+            .build()
+            .await
+            .unwrap();
+
+            let sentinel = Log::new("sentinel".as_bytes());
+            for log in [log.clone(), log.clone(), log.clone(), sentinel.clone()] {
+                topic.write_one(log).await.unwrap();
+            }
+
+            let mut r = topic.reader_from(Uuid::NIL).unwrap();
+            let mut count = 0;
+            while r.next().await.unwrap().unwrap().uuid != sentinel.uuid {
+                count += 1;
+            }
+            count
+        }
+    };
+
+    // Duplicates are only dropped with a dedup window, in both formats
+    for version in [FormatVersion::V1, FormatVersion::V2] {
+        assert_eq!(count(version, None).await, 3);
+        assert_eq!(count(version, Some(DedupWindow::Count(10))).await, 1);
+    }
+}
+
+#[tokio::test]
+async fn test_next_batch() {
+    init();
+
+    let dir = TempDir::new().unwrap();
+    let topic = TopicBuilder::new_with_dir("test", dir.path())
+        .unwrap()
+        .with_log_size(1 << 10)
+        .build()
+        .await
+        .unwrap();
+    let w = topic.writer();
+    let mut r = topic.reader();
+
+    // Spans several segments
+    for i in 0..50u32 {
+        w.write(i.to_le_bytes().as_slice()).await.unwrap();
+    }
+
+    // Wait for all logs to be written
+    let mut r2 = r.clone();
+    for _ in 0..50 {
+        r2.next().await.unwrap().unwrap();
+    }
+
+    let timeout = Duration::from_secs(5);
+    assert_eq!(
+        r.next_batch(10, usize::MAX, timeout).await.unwrap().len(),
+        10
+    );
+    // Each log's `Log::byte_len` is 28 bytes, and at least one is read
+    assert_eq!(r.next_batch(100, 80, timeout).await.unwrap().len(), 2);
+    assert_eq!(r.next_batch(100, 0, timeout).await.unwrap().len(), 1);
+
+    let logs = r.next_batch(100, usize::MAX, timeout).await.unwrap();
+    assert_eq!(logs.len(), 37);
+    assert_eq!(logs[36].body.as_slice(), 49u32.to_le_bytes());
+
+    let empty = r.next_batch(100, usize::MAX, Duration::from_millis(50));
+    assert!(empty.await.unwrap().is_empty());
+
+    // Wait for new logs
+    let write = async {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        w.write("hello".as_bytes()).await.unwrap();
+    };
+    let (logs, _) = tokio::join!(r.next_batch(100, usize::MAX, timeout), write);
+    assert_eq!(logs.unwrap()[0].body.as_slice(), b"hello");
+}
+
+#[tokio::test]
+async fn test_dir_lock() {
+    init();
+
+    let dir = TempDir::new().unwrap();
+    let build = || {
+        TopicBuilder::new_with_dir("test", dir.path())
+            .unwrap()
+            .build()
+    };
+
+    let topic = build().await.unwrap();
+    assert_eq!(topic.epoch(), Some(1));
+
+    // A second writer fails fast, readers are still allowed
+    let topic_dir = topic.config().topic_dir();
+    assert!(matches!(build().await, Err(ErrorType::Locked(d)) if d == topic_dir));
+    let ro = Topic::open_read_only(&topic_dir).unwrap();
+    assert_eq!(ro.epoch(), None);
+
+    // Released once the background task is finished, and the epoch is bumped
+    topic.stop();
+    topic.join().await.unwrap_err();
+    let topic = build().await.unwrap();
+    assert_eq!(topic.epoch(), Some(2));
+}
@@ -0,0 +1,195 @@
+#![cfg(feature = "server")]
+
+use std::time::Duration;
+
+use futures::StreamExt;
+use limlog::{
+    formats::{FormatVersion, Log},
+    server::{Client, Server},
+    ErrorType, Position, Topic, TopicBuilder,
+};
+use tempfile::TempDir;
+use tokio::net::TcpListener;
+use uuid7::Uuid;
+
+mod_use::mod_use!(common);
+
+#[tokio::test]
+async fn test_server() {
+    init();
+
+    let dir = TempDir::new().unwrap();
+    let server = Server::new();
+
+    for name in ["b", "a"] {
+        let topic = TopicBuilder::new_with_dir(name, dir.path())
+            .unwrap()
+            .with_max_records(2)
+            .build()
+            .await
+            .unwrap();
+        server.add_topic(topic);
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { server.serve(listener).await });
+
+    let mut client = Client::connect(addr).await.unwrap();
+
+    assert_eq!(client.list_topics().await.unwrap(), ["a", "b"]);
+
+    let first = client.produce("a", "hello".as_bytes()).await.unwrap();
+    let rest = client
+        .produce_batch("a", ["1", "2", "3"].map(str::as_bytes))
+        .await
+        .unwrap();
+    assert_eq!(rest.len(), 3);
+
+    // Produced logs are acknowledged once queued, they may not be written yet.
+    // Resuming from the returned UUID continues across segments.
+    let mut logs = vec![];
+    let mut next = Uuid::NIL;
+    while logs.len() < 4 {
+        let (mut read, n) = client
+            .consume("a", next, 3, Duration::from_millis(100))
+            .await
+            .unwrap();
+        logs.append(&mut read);
+        next = n;
+    }
+    assert_eq!(logs.len(), 4);
+    assert_eq!(logs[0].uuid, first);
+    assert_eq!(logs[0].body.as_slice(), b"hello");
+    assert_eq!(logs[1..].iter().map(|l| l.uuid).collect::<Vec<_>>(), rest);
+
+    let (logs, _) = client
+        .consume("a", rest[1], 10, Duration::from_millis(100))
+        .await
+        .unwrap();
+    assert_eq!(logs.iter().map(|l| l.uuid).collect::<Vec<_>>(), rest[1..]);
+
+    // Long-poll for the next log
+    let mut producer = Client::connect(addr).await.unwrap();
+    let produce = tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        producer.produce("a", "late".as_bytes()).await.unwrap()
+    });
+
+    let (logs, _) = client
+        .consume("a", next, 10, Duration::from_secs(10))
+        .await
+        .unwrap();
+    assert_eq!(logs.len(), 1);
+    assert_eq!(logs[0].uuid, produce.await.unwrap());
+
+    // Nothing to read
+    let (logs, next) = client
+        .consume("b", Uuid::NIL, 10, Duration::from_millis(10))
+        .await
+        .unwrap();
+    assert!(logs.is_empty());
+    assert_eq!(next, Uuid::NIL);
+
+    let err = client.produce("c", "hello".as_bytes()).await.unwrap_err();
+    assert!(matches!(err, ErrorType::Server(_)));
+}
+
+/// Serve `topic` and returns a client connected to it
+async fn serve(topic: Topic) -> Client {
+    let server = Server::new();
+    server.add_topic(topic);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { server.serve(listener).await });
+
+    Client::connect(addr).await.unwrap()
+}
+
+#[tokio::test]
+async fn test_consume_offset() {
+    init();
+
+    let dir = TempDir::new().unwrap();
+    let topic = TopicBuilder::new_with_dir("a", dir.path())
+        .unwrap()
+        .with_format_version(FormatVersion::V2)
+        .with_max_records(4)
+        .build()
+        .await
+        .unwrap();
+    let mut r = topic.reader();
+
+    // Queued together, so they're written in batches across segments
+    let logs = (0..10u32)
+        .map(|i| Log::new(i.to_le_bytes().as_slice()))
+        .collect::<Vec<_>>();
+    for log in &logs {
+        topic.write_one(log.clone()).await.unwrap();
+    }
+    for _ in &logs {
+        r.next().await.unwrap().unwrap();
+    }
+    let mut client = serve(topic).await;
+
+    // Positions resume in the middle of batches and across segments
+    let mut read = vec![];
+    let mut next = Position::default();
+    while read.len() < logs.len() {
+        let (mut batch, n) = client
+            .consume_at("a", next, 3, Duration::from_millis(100))
+            .await
+            .unwrap();
+        assert!(batch.len() <= 3);
+        read.append(&mut batch);
+        next = n;
+    }
+    assert_eq!(read, logs);
+
+    let (read, same) = client
+        .consume_at("a", next.clone(), 3, Duration::from_millis(10))
+        .await
+        .unwrap();
+    assert!(read.is_empty());
+    assert_eq!(same, next);
+}
+
+#[tokio::test]
+async fn test_consume_large() {
+    init();
+
+    let dir = TempDir::new().unwrap();
+    let topic = TopicBuilder::new_with_dir("a", dir.path())
+        .unwrap()
+        .with_format_version(FormatVersion::V2)
+        .build()
+        .await
+        .unwrap();
+    let mut r = topic.reader();
+
+    let logs = (0..8u8)
+        .map(|i| Log::new(vec![i; 3 << 20]))
+        .collect::<Vec<_>>();
+    for log in &logs {
+        topic.write_one(log.clone()).await.unwrap();
+    }
+    for _ in &logs {
+        r.next().await.unwrap().unwrap();
+    }
+    let mut client = serve(topic).await;
+
+    // Responses are split to fit in frames
+    let mut read = vec![];
+    let mut next = Uuid::NIL;
+    while read.len() < logs.len() {
+        let (mut batch, n) = client
+            .consume("a", next, 100, Duration::from_millis(100))
+            .await
+            .unwrap();
+        assert!(!batch.is_empty() && batch.len() < logs.len());
+        read.append(&mut batch);
+        next = n;
+    }
+    assert_eq!(read, logs);
+}
@@ -0,0 +1,51 @@
+use futures::{SinkExt, StreamExt};
+use limlog::{consts::SmallBytes, TopicBuilder};
+use tempfile::TempDir;
+use uuid7::Uuid;
+
+mod_use::mod_use!(common);
+
+#[tokio::test]
+async fn test_sink() {
+    init();
+
+    let dir = TempDir::new().unwrap();
+    let source = TopicBuilder::new_with_dir("source", dir.path())
+        .unwrap()
+        .with_channel_size(1)
+        .build()
+        .await
+        .unwrap();
+    let target = TopicBuilder::new_with_dir("target", dir.path())
+        .unwrap()
+        .with_channel_size(1)
+        .build()
+        .await
+        .unwrap();
+
+    let mut sink = source.writer().sink();
+    for i in 0..100u32 {
+        sink.send(SmallBytes::from_slice(&i.to_le_bytes()))
+            .await
+            .unwrap();
+    }
+
+    // Copy with UUIDs kept
+    source
+        .reader_from(Uuid::NIL)
+        .unwrap()
+        .take(100)
+        .forward(target.writer().sink())
+        .await
+        .unwrap();
+
+    let source = source.reader_from(Uuid::NIL).unwrap().take(100);
+    let target = target.reader_from(Uuid::NIL).unwrap().take(100);
+    let logs = source.zip(target).collect::<Vec<_>>().await;
+    assert_eq!(logs.len(), 100);
+    for (i, (a, b)) in logs.into_iter().enumerate() {
+        let (a, b) = (a.unwrap(), b.unwrap());
+        assert_eq!(a, b);
+        assert_eq!(a.body.as_slice(), (i as u32).to_le_bytes());
+    }
+}
@@ -0,0 +1,335 @@
+use futures::{StreamExt, TryStreamExt};
+use limlog::{
+    archive::{ArchiveBackend, LocalArchive, SegmentFile},
+    consts::HEADER_SIZE,
+    formats::{FormatVersion, Log},
+    DedupWindow, ErrorType, OrderingPolicy, Result, Topic, TopicBuilder,
+};
+use serde::Serialize;
+use tempfile::TempDir;
+use uuid7::Uuid;
+
+mod_use::mod_use!(common);
+
+#[tokio::test]
+async fn test_truncate_after() {
+    init();
+    test_truncate_after_impl(FormatVersion::V1).await;
+    test_truncate_after_impl(FormatVersion::V2).await;
+}
+
+#[tokio::test]
+async fn test_truncate_before() {
+    init();
+    test_truncate_before_impl(FormatVersion::V1).await;
+    test_truncate_before_impl(FormatVersion::V2).await;
+}
+
+async fn build(dir: &TempDir, version: FormatVersion) -> Topic {
+    TopicBuilder::new_with_dir("test", dir.path())
+        .unwrap()
+        .with_max_records(20)
+        .with_format_version(version)
+        .with_ordering_policy(OrderingPolicy::Reject)
+        .with_dedup_window(DedupWindow::Count(1000))
+        .build()
+        .await
+        .unwrap()
+}
+
+fn new_logs(n: u32) -> Vec<Log> {
+    (0..n)
+        .map(|i| Log::new(i.to_le_bytes().as_slice()))
+        .collect()
+}
+
+/// Write `logs` and wait until they're written
+async fn write(topic: &Topic, logs: Vec<Log>) -> Vec<Log> {
+    let mut r = topic.reader();
+    for log in &logs {
+        topic.write_one(log.clone()).await.unwrap();
+    }
+
+    for log in &logs {
+        assert_eq!(r.next().await.unwrap().unwrap().uuid, log.uuid);
+    }
+
+    logs
+}
+
+async fn uuids(topic: &Topic) -> Vec<Uuid> {
+    topic
+        .range(Uuid::NIL, Uuid::MAX)
+        .unwrap()
+        .map_ok(|log| log.uuid)
+        .try_collect()
+        .await
+        .unwrap()
+}
+
+fn segments(dir: &TempDir) -> usize {
+    std::fs::read_dir(dir.path().join("test"))
+        .unwrap()
+        .filter(|e| e.as_ref().unwrap().path().extension() == Some("limlog".as_ref()))
+        .count()
+}
+
+async fn test_truncate_after_impl(version: FormatVersion) {
+    let dir = TempDir::new().unwrap();
+    let topic = build(&dir, version).await;
+
+    // Spans several segments
+    let logs = write(&topic, new_logs(100)).await;
+    let all = logs.iter().map(|log| log.uuid).collect::<Vec<_>>();
+
+    let mut behind = topic.reader_from(all[10]).unwrap();
+    let mut ahead = topic.reader_from(all[80]).unwrap();
+    assert_eq!(ahead.next().await.unwrap().unwrap().uuid, all[80]);
+
+    topic.truncate_after(all[49]).await.unwrap();
+
+    assert_eq!(uuids(&topic).await, all[..50]);
+    assert!(topic.get(all[50]).unwrap().is_none());
+    assert_eq!(topic.get(all[49]).unwrap().unwrap(), logs[49]);
+
+    // Logs after the cut can't be read anymore
+    assert!(matches!(
+        ahead.next().await.unwrap(),
+        Err(ErrorType::Truncated)
+    ));
+
+    // Dropped UUIDs can be written again, before the ones of new logs
+    let new = write(
+        &topic,
+        std::iter::once(logs[50].clone())
+            .chain(new_logs(10))
+            .collect(),
+    )
+    .await;
+    let expected = all[..50]
+        .iter()
+        .copied()
+        .chain(new.iter().map(|log| log.uuid))
+        .collect::<Vec<_>>();
+
+    // Readers before the cut go on with new logs
+    let read = (&mut behind)
+        .take(expected.len() - 10)
+        .map_ok(|log| log.uuid)
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+    assert_eq!(read, expected[10..]);
+    assert_eq!(uuids(&topic).await, expected);
+
+    // The truncation is persisted, and the cut segment is repaired when opened
+    drop((behind, ahead));
+    topic.stop();
+    assert!(matches!(topic.join().await, Err(ErrorType::Shutdown)));
+    let topic = build(&dir, version).await;
+    assert_eq!(uuids(&topic).await, expected);
+
+    // Dropping everything
+    topic.truncate_after(Uuid::NIL).await.unwrap();
+    assert!(uuids(&topic).await.is_empty());
+    assert_eq!(segments(&dir), 1);
+}
+
+async fn test_truncate_before_impl(version: FormatVersion) {
+    let dir = TempDir::new().unwrap();
+    let topic = build(&dir, version).await;
+
+    let logs = write(&topic, new_logs(100)).await;
+    let all = logs.iter().map(|log| log.uuid).collect::<Vec<_>>();
+    let before = segments(&dir);
+
+    let mut behind = topic.reader_from(all[10]).unwrap();
+    let mut ahead = topic.reader_from(all[80]).unwrap();
+
+    topic.truncate_before(all[60]).await.unwrap();
+
+    // Segments before the one with the start are removed, and one is rolled
+    assert_eq!(uuids(&topic).await, all[60..]);
+    assert_eq!(segments(&dir), before - 2);
+    assert!(topic.get(all[59]).unwrap().is_none());
+    assert_eq!(topic.get(all[60]).unwrap().unwrap(), logs[60]);
+
+    let mut r = topic.reader_from(Uuid::NIL).unwrap();
+    assert_eq!(r.next().await.unwrap().unwrap().uuid, all[60]);
+
+    assert!(matches!(
+        behind.next().await.unwrap(),
+        Err(ErrorType::Truncated)
+    ));
+    assert_eq!(ahead.next().await.unwrap().unwrap().uuid, all[80]);
+
+    drop((r, behind, ahead));
+    topic.stop();
+    assert!(matches!(topic.join().await, Err(ErrorType::Shutdown)));
+    let topic = build(&dir, version).await;
+    assert_eq!(uuids(&topic).await, all[60..]);
+
+    // Dropping everything, new logs are kept
+    let last = *all.last().unwrap();
+    topic.truncate_before(last).await.unwrap();
+    let new = write(&topic, new_logs(10)).await;
+    let expected = std::iter::once(last)
+        .chain(new.iter().map(|log| log.uuid))
+        .collect::<Vec<_>>();
+    assert_eq!(uuids(&topic).await, expected);
+}
+
+/// Same layout as the plan a truncation persists before changing files
+#[derive(Serialize)]
+struct Plan {
+    remove: Vec<String>,
+    cut: Option<Cut>,
+}
+
+#[derive(Serialize)]
+struct Cut {
+    segment: String,
+    end: u64,
+    batch: Option<(u64, u32)>,
+}
+
+#[tokio::test]
+async fn test_truncate_replay() {
+    init();
+
+    let dir = TempDir::new().unwrap();
+    let topic_dir = dir.path().join("test");
+    let topic = build(&dir, FormatVersion::V1).await;
+    let logs = write(&topic, new_logs(100)).await;
+    let all = logs.iter().map(|log| log.uuid).collect::<Vec<_>>();
+
+    topic.stop();
+    topic.join().await.unwrap_err();
+
+    let mut names = std::fs::read_dir(&topic_dir)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension() == Some("limlog".as_ref()))
+        .map(|p| p.file_stem().unwrap().to_str().unwrap().to_owned())
+        .collect::<Vec<_>>();
+    names.sort();
+    let path = |name: &String, ext| topic_dir.join(name).with_extension(ext);
+
+    // Each segment has 20 logs of 28 bytes
+    for name in &names[..5] {
+        let len = std::fs::metadata(path(name, "limlog")).unwrap().len();
+        assert_eq!(len, (HEADER_SIZE + 20 * 28) as u64);
+    }
+
+    // Crashed while truncating after the 50th log, once the last segment and the
+    // index of the one before were removed
+    let plan = Plan {
+        remove: vec![names[4].clone(), names[3].clone()],
+        cut: Some(Cut {
+            segment: names[2].clone(),
+            end: 10 * 28,
+            batch: None,
+        }),
+    };
+    std::fs::write(
+        topic_dir.join("truncate.pending"),
+        bincode::serialize(&plan).unwrap(),
+    )
+    .unwrap();
+    std::fs::remove_file(path(&names[4], "idx")).unwrap();
+    std::fs::remove_file(path(&names[4], "limlog")).unwrap();
+    std::fs::remove_file(path(&names[3], "idx")).unwrap();
+
+    // The plan is finished and the cut segment repaired when opened
+    let topic = build(&dir, FormatVersion::V1).await;
+    assert!(!topic_dir.join("truncate.pending").exists());
+    assert!(!path(&names[3], "limlog").exists());
+    assert_eq!(uuids(&topic).await, all[..50]);
+
+    let len = std::fs::metadata(path(&names[2], "limlog")).unwrap().len();
+    assert_eq!(len, (HEADER_SIZE + 10 * 28) as u64);
+    let len = std::fs::metadata(path(&names[2], "idx")).unwrap().len();
+    assert_eq!(len, (HEADER_SIZE + 10 * 24) as u64);
+
+    // New logs go on after the last one kept
+    let new = write(&topic, new_logs(10)).await;
+    let expected = all[..50]
+        .iter()
+        .copied()
+        .chain(new.iter().map(|log| log.uuid))
+        .collect::<Vec<_>>();
+    assert_eq!(uuids(&topic).await, expected);
+}
+
+/// A [`LocalArchive`] that can't remove segments
+#[derive(Debug)]
+struct AppendOnly(LocalArchive);
+
+impl ArchiveBackend for AppendOnly {
+    fn upload(&self, segment: &str, file: SegmentFile, data: &mut dyn std::io::Read) -> Result<()> {
+        self.0.upload(segment, file, data)
+    }
+
+    fn download(&self, segment: &str, file: SegmentFile, dest: &std::path::Path) -> Result<bool> {
+        self.0.download(segment, file, dest)
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        self.0.list()
+    }
+}
+
+async fn build_archived(dir: &TempDir, archive: impl ArchiveBackend) -> Topic {
+    TopicBuilder::new_with_dir("test", dir.path())
+        .unwrap()
+        .with_max_records(20)
+        .with_archive(archive)
+        .build()
+        .await
+        .unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_truncate_archived() {
+    init();
+
+    let dir = TempDir::new().unwrap();
+    let archive_dir = TempDir::new().unwrap();
+    let topic_dir = dir.path().join("test");
+    let local = LocalArchive::new(archive_dir.path()).unwrap();
+
+    let topic = build_archived(&dir, AppendOnly(local.clone())).await;
+    let logs = write(&topic, new_logs(100)).await;
+    let all = logs.iter().map(|log| log.uuid).collect::<Vec<_>>();
+    while local.list().unwrap().len() < 4 {
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+
+    // Truncations fail before anything is changed, and don't fail later ones or
+    // reopening
+    let unsupported = |res: Result<()>| {
+        assert!(matches!(
+            res,
+            Err(ErrorType::Io(e)) if e.kind() == std::io::ErrorKind::Unsupported
+        ));
+    };
+    unsupported(topic.truncate_after(all[49]).await);
+    unsupported(topic.truncate_before(all[49]).await);
+    unsupported(topic.truncate_after(all[49]).await);
+    assert!(!topic_dir.join("truncate.pending").exists());
+    assert!(!topic_dir.join("start").exists());
+    assert_eq!(uuids(&topic).await, all);
+
+    topic.stop();
+    topic.join().await.unwrap_err();
+    let topic = build_archived(&dir, AppendOnly(local.clone())).await;
+    assert_eq!(uuids(&topic).await, all);
+    topic.stop();
+    topic.join().await.unwrap_err();
+
+    // Archived segments are removed with a backend that can
+    let topic = build_archived(&dir, local.clone()).await;
+    topic.truncate_after(all[49]).await.unwrap();
+    assert_eq!(uuids(&topic).await, all[..50]);
+    assert!(local.list().unwrap().len() < 4);
+}
@@ -0,0 +1,69 @@
+use futures::StreamExt;
+use limlog::{
+    typed::{Bincode, Codec, TypedTopic},
+    ErrorType, TopicBuilder,
+};
+use serde::{Deserialize, Serialize};
+use tap::Pipe;
+use tempfile::TempDir;
+
+mod_use::mod_use!(common);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct Event {
+    id: u64,
+    name: String,
+}
+
+async fn test_codec<C: Codec>() {
+    let dir = TempDir::new().unwrap();
+    let topic = TopicBuilder::new_with_dir("test", dir.path())
+        .unwrap()
+        .build()
+        .await
+        .unwrap()
+        .pipe(TypedTopic::<Event, C>::new);
+
+    let w = topic.writer();
+    let mut r = topic.reader();
+
+    let events = (0..10)
+        .map(|id| Event {
+            id,
+            name: format!("event {id}"),
+        })
+        .collect::<Vec<_>>();
+    for event in &events {
+        w.write(event).await.unwrap();
+    }
+
+    // Not an `Event`
+    topic
+        .topic()
+        .writer()
+        .write([0xff].as_slice())
+        .await
+        .unwrap();
+    w.write(&events[0]).await.unwrap();
+
+    for event in &events {
+        assert_eq!(&r.next().await.unwrap().unwrap(), event);
+    }
+
+    let err = r.next().await.unwrap().unwrap_err();
+    assert!(matches!(err, ErrorType::Decode(_)));
+    assert_eq!(r.next().await.unwrap().unwrap(), events[0]);
+}
+
+#[tokio::test]
+async fn test_bincode() {
+    init();
+    test_codec::<Bincode>().await;
+}
+
+#[cfg(feature = "json")]
+#[tokio::test]
+async fn test_json() {
+    init();
+    test_codec::<limlog::typed::Json>().await;
+}
@@ -1,21 +1,27 @@
 #![feature(cursor_remaining)]
 
-use std::io::{Cursor, Seek, SeekFrom};
+use std::{
+    io::{Cursor, Seek, SeekFrom},
+    time::Duration,
+};
 
 use bincode::Options;
+use futures::{StreamExt, TryStreamExt};
 use limlog::{
     bincode_option,
     consts::{HEADER_SIZE, INDEX_SIZE},
-    formats::{Log, UuidIndex},
-    Result,
+    formats::{Attributes, Header, Log, UuidIndex},
+    Result, TopicBuilder,
 };
 use rand::{thread_rng, Rng};
 use tap::{Pipe, Tap};
+use tempfile::TempDir;
 use tokio::{
     fs,
     io::{AsyncReadExt, BufReader},
 };
 use tracing::{info, warn};
+use uuid7::Uuid;
 
 mod_use::mod_use!(common);
 
@@ -26,7 +32,7 @@ async fn test_index() {
 }
 
 async fn test_index_impl() -> Result<()> {
-    let (_tmp, dir) = write_several(thread_rng().gen_range(10000..100000)).await?;
+    let (_tmp, dir) = write_several_stopped(thread_rng().gen_range(10000..100000)).await?;
 
     let mut read_dir = fs::read_dir(&dir).await?;
 
@@ -95,3 +101,81 @@ fn validate(mut idx: Cursor<Vec<u8>>, mut log: Cursor<Vec<u8>>) -> Result<bool>
     }
     Ok(false)
 }
+
+/// `.limlog` and `.idx` files in `dir`, skipping the lock of the topic
+fn segment_files(dir: &std::path::Path) -> Vec<std::path::PathBuf> {
+    std::fs::read_dir(dir)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| {
+            matches!(
+                p.extension().and_then(|e| e.to_str()),
+                Some("limlog" | "idx")
+            )
+        })
+        .collect()
+}
+
+fn header_of(path: &std::path::Path) -> Header {
+    let bytes = std::fs::read(path).unwrap();
+    Header::from_bytes(bytes[..HEADER_SIZE].try_into().unwrap())
+}
+
+#[tokio::test]
+async fn test_watermark() {
+    init();
+
+    let (_tmp, dir) = write_several_stopped(100).await.unwrap();
+
+    for path in segment_files(&dir) {
+        let attr = header_of(&path).attributes();
+        let len = std::fs::metadata(&path).unwrap().len();
+
+        assert!(attr.has(Attributes::WATERMARK | Attributes::CLEAN));
+        assert_eq!(attr.committed + HEADER_SIZE as u64, len);
+    }
+
+    // Copy the active segment without closing it, as if the writer crashed
+    let src = TempDir::new().unwrap();
+    let topic = TopicBuilder::new_with_dir("src", src.path())
+        .unwrap()
+        .with_log_size(1 << 16)
+        .with_index_size(1 << 12)
+        .build()
+        .await
+        .unwrap();
+    let w = topic.writer();
+    let mut r = topic.reader();
+    for _ in 0..100 {
+        w.write("hello".as_bytes()).await.unwrap();
+    }
+    for _ in 0..100 {
+        r.next().await.unwrap().unwrap();
+    }
+    // The watermark is advanced once the logs are synced
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let dst = TempDir::new().unwrap();
+    let dst_dir = dst.path().join("dst");
+    std::fs::create_dir(&dst_dir).unwrap();
+    for path in segment_files(&topic.config().topic_dir()) {
+        let attr = header_of(&path).attributes();
+        assert!(!attr.has(Attributes::CLEAN));
+
+        std::fs::copy(&path, dst_dir.join(path.file_name().unwrap())).unwrap();
+    }
+
+    // Only committed logs are read, not the preallocated tail
+    let topic = TopicBuilder::new_with_dir("dst", dst.path())
+        .unwrap()
+        .build()
+        .await
+        .unwrap();
+    let logs = topic
+        .range(Uuid::NIL, Uuid::MAX)
+        .unwrap()
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+    assert_eq!(logs.len(), 100);
+}